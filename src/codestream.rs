@@ -0,0 +1,962 @@
+use std::marker::PhantomData;
+use nalgebra::DMatrix;
+use crate::color_transform::{ComponentTransform, synthesis_gains};
+use crate::conversion::ConvertPixel;
+use crate::jp2::{ColourSpace, Jp2Header, Resolution, write_jp2};
+use crate::my_image::Image;
+use crate::pixel_type::PixelTrait;
+use crate::progression::{Progression, ProgressionOrder};
+use crate::quantization::{QuantizationStyle, Quantizer, StepSize, dynamic_range};
+use crate::rate_control::{LayerTarget, SizeLimit, TruncationPoints, allocate_layers, limit_layers};
+use crate::subband::Decomposition;
+use crate::subsampling::{ChromaSiting, ChromaSubsampling, DownsamplingFilter, downsample};
+use crate::tier1::{BlockCoder, CodeBlockStyle, CodedSubband};
+use crate::tier2::{DEFAULT_PRECINCT_SIZE, PacketLayout, PrecinctSize, encode_packets, precinct_block_size, resolution_ranges};
+use crate::wavelet::{Wavelet, synthesis_norm};
+
+/*
+    Crate of the JPEG 2000 codestream (Annex A of the spec): the marker segments of the main header
+    and of the tile-part headers, and the encoder writing a whole image as a raw codestream (.j2k).
+
+    A codestream is made of:
+    - SOC (start of codestream),
+    - the main header: SIZ (image and tile size), COD (coding style) and QCD (quantization),
+    - for each tile-part: SOT (start of tile-part), SOD (start of data) and the packets,
+    - EOC (end of codestream).
+    Each marker segment can be written and read back from its parameters (the bytes after its length).
+*/
+
+// Markers
+pub const SOC: u16 = 0xFF4F;
+pub const SIZ: u16 = 0xFF51;
+pub const COD: u16 = 0xFF52;
+pub const QCD: u16 = 0xFF5C;
+pub const SOT: u16 = 0xFF90;
+pub const SOD: u16 = 0xFF93;
+pub const EOC: u16 = 0xFFD9;
+pub const COC: u16 = 0xFF53;
+pub const QCC: u16 = 0xFF5D;
+pub const RGN: u16 = 0xFF5E;
+pub const POC: u16 = 0xFF5F;
+pub const PPM: u16 = 0xFF60;
+pub const PPT: u16 = 0xFF61;
+
+// Precision (bits per sample) of the components of our images
+const PRECISION: u8 = 8;
+
+pub fn write_u16(bytes: &mut Vec<u8>, value: u16) { bytes.extend_from_slice(&value.to_be_bytes()); }
+pub fn write_u32(bytes: &mut Vec<u8>, value: u32) { bytes.extend_from_slice(&value.to_be_bytes()); }
+
+/*
+    Read big endian values at the given position (panics if the data is too short).
+*/
+pub fn read_u16(bytes: &[u8], position: usize) -> u16 {
+    match bytes.get(position..position + 2) {
+        Some(value) => u16::from_be_bytes([value[0], value[1]]),
+        None => panic!("Unexpected end of data at byte {}.", position),
+    }
+}
+
+pub fn read_u32(bytes: &[u8], position: usize) -> u32 {
+    match bytes.get(position..position + 4) {
+        Some(value) => u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+        None => panic!("Unexpected end of data at byte {}.", position),
+    }
+}
+
+/*
+    Write a marker segment: its marker, its length (which counts itself but not the marker) and its parameters.
+*/
+pub fn write_segment(bytes: &mut Vec<u8>, marker: u16, parameters: &[u8]) {
+    write_u16(bytes, marker);
+    write_u16(bytes, parameters.len() as u16 + 2);
+    bytes.extend_from_slice(parameters);
+}
+
+/*
+    Component of the image as described by SIZ: its precision, whether its samples are signed,
+    and its subsampling factors on the reference grid.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Component {
+    precision: u8,
+    signed: bool,
+    dx: u8,
+    dy: u8,
+}
+
+impl Component {
+    pub fn new(precision: u8, signed: bool, dx: u8, dy: u8) -> Self {
+        if !(1..=38).contains(&precision) || dx == 0 || dy == 0 {
+            panic!("Invalid component: {} bits, subsampling {}x{}.", precision, dx, dy);
+        }
+        Component {precision, signed, dx, dy}
+    }
+
+    pub fn get_precision(&self) -> u8 { self.precision }
+    pub fn get_signed(&self) -> bool { self.signed }
+    pub fn get_dx(&self) -> u8 { self.dx }
+    pub fn get_dy(&self) -> u8 { self.dy }
+}
+
+/*
+    Image and tile size (SIZ marker segment): the size of the image and its offset on the reference
+    grid, the size of the tiles and the origin of their grid, and the components.
+
+    The tiles split the reference grid from the origin of their grid, so the first and last ones can
+    be cut by the borders of the image. A sample of a component with the subsampling factors dx and dy
+    lies at (x * dx, y * dy) on the reference grid, so a tile of a component goes from ceil(x0 / dx)
+    to ceil(x1 / dx) (and the same vertically) for a tile going from x0 to x1.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageAndTileSize {
+    width: u32,
+    height: u32,
+    origin: (u32, u32),
+    tile_width: u32,
+    tile_height: u32,
+    tile_origin: (u32, u32),
+    components: Vec<Component>,
+}
+
+// Bounds of a rectangle: its top left corner (included) and its bottom right corner (excluded)
+pub type Bounds = ((u32, u32), (u32, u32));
+
+impl ImageAndTileSize {
+    /*
+        Create the size of an image split in tiles of the given size, the image and the first tile
+        starting at the given offsets on the reference grid.
+    */
+    pub fn tiled(width: u32, height: u32, offset: (u32, u32), tile_origin: (u32, u32), tile_size: (u32, u32), components: Vec<Component>) -> Self {
+        let size = ImageAndTileSize {width, height, origin: offset, tile_width: tile_size.0, tile_height: tile_size.1, tile_origin, components};
+        size.check_tiles();
+        size
+    }
+
+    pub fn get_width(&self) -> u32 { self.width }
+    pub fn get_height(&self) -> u32 { self.height }
+    pub fn get_components(&self) -> &Vec<Component> { &self.components }
+
+    /*
+        Check that the first tile holds the top left corner of the image and that the tiles can be
+        indexed in a SOT marker segment.
+    */
+    fn check_tiles(&self) {
+        let ((x0, y0), (tx0, ty0)) = (self.origin, self.tile_origin);
+        if self.tile_width == 0 || self.tile_height == 0 || self.width == 0 || self.height == 0 {
+            panic!("The image and its tiles can not be empty.");
+        }
+        if tx0 > x0 || ty0 > y0 || tx0 + self.tile_width <= x0 || ty0 + self.tile_height <= y0 {
+            panic!("The first tile must hold the top left corner of the image.");
+        }
+        if self.get_tile_count() > u16::MAX as usize {
+            panic!("An image can not have more than 65535 tiles.");
+        }
+    }
+
+    /*
+        Get the number of tiles horizontally and vertically.
+    */
+    pub fn get_tile_counts(&self) -> (u32, u32) {
+        let ((x0, y0), (tx0, ty0)) = (self.origin, self.tile_origin);
+        ((x0 + self.width - tx0).div_ceil(self.tile_width), (y0 + self.height - ty0).div_ceil(self.tile_height))
+    }
+
+    pub fn get_tile_count(&self) -> usize {
+        let (wide, high) = self.get_tile_counts();
+        wide as usize * high as usize
+    }
+
+    /*
+        Get the bounds of a tile on the reference grid (the tiles are numbered in raster order).
+    */
+    pub fn get_tile_bounds(&self, tile: usize) -> Bounds {
+        let (wide, _) = self.get_tile_counts();
+        let (p, q) = (tile as u32 % wide, tile as u32 / wide);
+        let ((x0, y0), (tx0, ty0)) = (self.origin, self.tile_origin);
+        (
+            ((tx0 + p * self.tile_width).max(x0), (ty0 + q * self.tile_height).max(y0)),
+            ((tx0 + (p + 1) * self.tile_width).min(x0 + self.width), (ty0 + (q + 1) * self.tile_height).min(y0 + self.height)),
+        )
+    }
+
+    /*
+        Get the bounds of a tile of a component, in the coordinates of the component.
+    */
+    pub fn get_tile_component_bounds(&self, tile: usize, component: usize) -> Bounds {
+        let ((x0, y0), (x1, y1)) = self.get_tile_bounds(tile);
+        let (dx, dy) = (self.components[component].dx as u32, self.components[component].dy as u32);
+        ((x0.div_ceil(dx), y0.div_ceil(dy)), (x1.div_ceil(dx), y1.div_ceil(dy)))
+    }
+
+    /*
+        Get the bounds of a whole component, in its coordinates.
+    */
+    pub fn get_component_bounds(&self, component: usize) -> Bounds {
+        let (x0, y0) = self.origin;
+        let (dx, dy) = (self.components[component].dx as u32, self.components[component].dy as u32);
+        ((x0.div_ceil(dx), y0.div_ceil(dy)), ((x0 + self.width).div_ceil(dx), (y0 + self.height).div_ceil(dy)))
+    }
+
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        let mut parameters = Vec::new();
+        // Capabilities (Rsiz): JPEG 2000 part 1 only
+        write_u16(&mut parameters, 0);
+        let ((x0, y0), (tx0, ty0)) = (self.origin, self.tile_origin);
+        for value in [x0 + self.width, y0 + self.height, x0, y0, self.tile_width, self.tile_height, tx0, ty0] {
+            write_u32(&mut parameters, value);
+        }
+        write_u16(&mut parameters, self.components.len() as u16);
+        for component in &self.components {
+            parameters.push((component.precision - 1) | (component.signed as u8) << 7);
+            parameters.push(component.dx);
+            parameters.push(component.dy);
+        }
+
+        write_segment(bytes, SIZ, &parameters);
+    }
+
+    pub fn read(parameters: &[u8]) -> Self {
+        let values: Vec<u32> = (0..8).map(|i| read_u32(parameters, 2 + 4 * i)).collect();
+        let (x1, y1, x0, y0) = (values[0], values[1], values[2], values[3]);
+        let (tile_width, tile_height, tile_x0, tile_y0) = (values[4], values[5], values[6], values[7]);
+        if x0 >= x1 || y0 >= y1 {
+            panic!("The image of the codestream is empty.");
+        }
+
+        let count = read_u16(parameters, 34) as usize;
+        let components = (0..count).map(|i| {
+            let component = parameters.get(36 + 3 * i..39 + 3 * i).unwrap_or_else(|| panic!("SIZ is too short."));
+            if component[1] == 0 || component[2] == 0 {
+                panic!("The subsampling factors of a component must be positive.");
+            }
+            Component::new((component[0] & 0x7F) + 1, component[0] & 0x80 != 0, component[1], component[2])
+        }).collect();
+
+        let size = ImageAndTileSize {
+            width: x1 - x0,
+            height: y1 - y0,
+            origin: (x0, y0),
+            tile_width,
+            tile_height,
+            tile_origin: (tile_x0, tile_y0),
+            components,
+        };
+        size.check_tiles();
+        size
+    }
+}
+
+/*
+    Coding style default (COD marker segment): the progression order, the number of layers, whether
+    a component transform is used, the number of decomposition levels, the code-block size and style,
+    the wavelet, and the size of the precincts of each resolution level (none for the default precincts).
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodingStyle {
+    progression_order: ProgressionOrder,
+    layer_count: u16,
+    component_transform: bool,
+    level_count: u8,
+    block_width: u32,
+    block_height: u32,
+    block_style: CodeBlockStyle,
+    transform_code: u8,
+    precinct_sizes: Vec<PrecinctSize>,
+}
+
+impl CodingStyle {
+    pub fn new(progression_order: ProgressionOrder, layer_count: u16, component_transform: bool, level_count: u8, block_coder: &BlockCoder, transform_code: u8, precinct_sizes: Vec<PrecinctSize>) -> Self {
+        if !precinct_sizes.is_empty() && precinct_sizes.len() != level_count as usize + 1 {
+            panic!("{} precinct sizes are given for {} resolution levels.", precinct_sizes.len(), level_count + 1);
+        }
+        CodingStyle {
+            progression_order,
+            layer_count,
+            component_transform,
+            level_count,
+            block_width: block_coder.get_block_width(),
+            block_height: block_coder.get_block_height(),
+            block_style: block_coder.get_style(),
+            transform_code,
+            precinct_sizes,
+        }
+    }
+
+    pub fn get_progression_order(&self) -> ProgressionOrder { self.progression_order }
+    pub fn get_layer_count(&self) -> u16 { self.layer_count }
+    pub fn get_component_transform(&self) -> bool { self.component_transform }
+    pub fn get_level_count(&self) -> u8 { self.level_count }
+    pub fn get_transform_code(&self) -> u8 { self.transform_code }
+    pub fn get_precinct_sizes(&self) -> &Vec<PrecinctSize> { &self.precinct_sizes }
+
+    pub fn get_precinct_size(&self, resolution: usize) -> PrecinctSize {
+        self.precinct_sizes.get(resolution).copied().unwrap_or(DEFAULT_PRECINCT_SIZE)
+    }
+
+    /*
+        Get a tier-1 coder with the code-block size and style.
+    */
+    pub fn get_block_coder(&self) -> BlockCoder {
+        let mut block_coder = BlockCoder::new();
+        block_coder.set_block_size(self.block_width, self.block_height);
+        block_coder.set_style(self.block_style);
+        block_coder
+    }
+
+    /*
+        Get the tier-1 coder of each subband (in resolution order), whose code-blocks fit in the
+        precincts of its resolution level.
+    */
+    pub fn get_subband_block_coders(&self) -> Vec<BlockCoder> {
+        let block_coder = self.get_block_coder();
+        resolution_ranges(3 * self.level_count as usize + 1).into_iter().enumerate().flat_map(|(resolution, range)| {
+            let (width, height) = precinct_block_size(resolution, self.get_precinct_size(resolution));
+            vec![block_coder.limit_block_size(width, height); range.len()]
+        }).collect()
+    }
+
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        let mut parameters = Vec::new();
+        // Coding style (Scod): default or custom precincts, no SOP nor EPH markers
+        parameters.push(!self.precinct_sizes.is_empty() as u8);
+        // Progression order, number of layers and component transform
+        parameters.push(self.progression_order.get_code());
+        write_u16(&mut parameters, self.layer_count);
+        parameters.push(self.component_transform as u8);
+        // Decomposition levels, code-block size (exponents minus 2) and style, and wavelet
+        parameters.push(self.level_count);
+        parameters.push(self.block_width.ilog2() as u8 - 2);
+        parameters.push(self.block_height.ilog2() as u8 - 2);
+        parameters.push(self.block_style.get_code());
+        parameters.push(self.transform_code);
+        // Precinct sizes of the resolution levels, from the lowest one
+        for (width, height) in &self.precinct_sizes {
+            parameters.push(width | height << 4);
+        }
+
+        write_segment(bytes, COD, &parameters);
+    }
+
+    pub fn read(parameters: &[u8]) -> Self {
+        if parameters.len() < 10 {
+            panic!("COD is too short.");
+        }
+        if parameters[0] & !1 != 0 {
+            panic!("SOP and EPH markers are not supported.");
+        }
+        if parameters[6] > 8 || parameters[7] > 8 || parameters[6] + parameters[7] > 8 {
+            panic!("Invalid code-block size.");
+        }
+
+        let level_count = parameters[5];
+        let precinct_sizes: Vec<PrecinctSize> = match parameters[0] & 1 {
+            0 => Vec::new(),
+            _ => {
+                let sizes = parameters.get(10..11 + level_count as usize).unwrap_or_else(|| panic!("COD is too short."));
+                sizes.iter().map(|size| (size & 0xF, size >> 4)).collect()
+            },
+        };
+        if precinct_sizes.iter().skip(1).any(|&(width, height)| width == 0 || height == 0) {
+            panic!("Invalid precinct size.");
+        }
+
+        CodingStyle {
+            progression_order: ProgressionOrder::from_code(parameters[1]),
+            layer_count: read_u16(parameters, 2),
+            component_transform: parameters[4] != 0,
+            level_count,
+            block_width: 1 << (parameters[6] + 2),
+            block_height: 1 << (parameters[7] + 2),
+            block_style: CodeBlockStyle::from_code(parameters[8]),
+            transform_code: parameters[9],
+            precinct_sizes,
+        }
+    }
+}
+
+/*
+    Quantization default (QCD marker segment): the quantization style, the number of guard bits and
+    the signalled step sizes (exponents only without quantization).
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantizationDefault {
+    style: QuantizationStyle,
+    guard_bits: u8,
+    step_sizes: Vec<StepSize>,
+}
+
+impl QuantizationDefault {
+    pub fn new(style: QuantizationStyle, guard_bits: u8, step_sizes: Vec<StepSize>) -> Self {
+        QuantizationDefault {style, guard_bits, step_sizes}
+    }
+
+    pub fn get_style(&self) -> QuantizationStyle { self.style }
+    pub fn get_guard_bits(&self) -> u8 { self.guard_bits }
+    pub fn get_step_sizes(&self) -> &Vec<StepSize> { &self.step_sizes }
+
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        let mut parameters = vec![self.guard_bits << 5 | self.style.get_code()];
+        for step_size in &self.step_sizes {
+            match self.style {
+                QuantizationStyle::NoQuantization => parameters.push(step_size.get_exponent() << 3),
+                _ => write_u16(&mut parameters, (step_size.get_exponent() as u16) << 11 | step_size.get_mantissa()),
+            }
+        }
+
+        write_segment(bytes, QCD, &parameters);
+    }
+
+    pub fn read(parameters: &[u8]) -> Self {
+        let style = QuantizationStyle::from_code(parameters[0] & 0x1F);
+        let step_sizes = match style {
+            QuantizationStyle::NoQuantization => parameters[1..].iter().map(|value| StepSize::new(value >> 3, 0)).collect(),
+            _ => (1..parameters.len() - 1).step_by(2).map(|position| {
+                let value = read_u16(parameters, position);
+                StepSize::new((value >> 11) as u8, value & 0x7FF)
+            }).collect(),
+        };
+
+        QuantizationDefault {style, guard_bits: parameters[0] >> 5, step_sizes}
+    }
+}
+
+/*
+    Progression order change (POC marker segment): the progressions of the packets, one after the other,
+    instead of the progression order of COD. The indices of the components are written on 2 bytes
+    when there are more than 256 components.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressionOrderChange {
+    progressions: Vec<Progression>,
+}
+
+impl ProgressionOrderChange {
+    pub fn new(progressions: Vec<Progression>) -> Self {
+        if progressions.is_empty() {
+            panic!("A progression order change needs at least one progression.");
+        }
+        ProgressionOrderChange {progressions}
+    }
+
+    pub fn get_progressions(&self) -> &Vec<Progression> { &self.progressions }
+
+    pub fn write(&self, bytes: &mut Vec<u8>, component_count: usize) {
+        let write_component = |parameters: &mut Vec<u8>, component: usize| match component_count > 256 {
+            true => write_u16(parameters, component as u16),
+            // An end of 256 is written 0
+            false => parameters.push(component as u8),
+        };
+
+        let mut parameters = Vec::new();
+        for progression in &self.progressions {
+            parameters.push(progression.get_resolution_start() as u8);
+            write_component(&mut parameters, progression.get_component_start());
+            write_u16(&mut parameters, progression.get_layer_end() as u16);
+            parameters.push(progression.get_resolution_end() as u8);
+            write_component(&mut parameters, progression.get_component_end().min(component_count));
+            parameters.push(progression.get_order().get_code());
+        }
+
+        write_segment(bytes, POC, &parameters);
+    }
+
+    pub fn read(parameters: &[u8], component_count: usize) -> Self {
+        let wide = component_count > 256;
+        let size = if wide { 9 } else { 7 };
+        let read_component = |position: usize| match wide {
+            true => read_u16(parameters, position) as usize,
+            false => parameters[position] as usize,
+        };
+
+        let progressions = parameters.chunks_exact(size).enumerate().map(|(i, entry)| {
+            let position = i * size;
+            let component_end = match read_component(position + size - 2 - wide as usize) {
+                0 if wide => 16384,
+                0 => 256,
+                end => end,
+            };
+            Progression::new(
+                ProgressionOrder::from_code(entry[size - 1]),
+                read_u16(parameters, position + 2 + wide as usize) as usize,
+                entry[0] as usize,
+                entry[4 + wide as usize] as usize,
+                read_component(position + 1),
+                component_end,
+            )
+        }).collect();
+
+        ProgressionOrderChange::new(progressions)
+    }
+}
+
+/*
+    Write a tile-part: SOT (with the index of the tile, the length of the tile-part, its index
+    and the number of tile-parts of the tile), SOD and the packets.
+*/
+pub fn write_tile_part(bytes: &mut Vec<u8>, tile: u16, part: u8, part_count: u8, data: &[u8]) {
+    let mut parameters = Vec::new();
+    write_u16(&mut parameters, tile);
+    // Length from the start of SOT to the end of the data: SOT (12 bytes), SOD (2 bytes) and the data
+    write_u32(&mut parameters, (14 + data.len()) as u32);
+    parameters.push(part);
+    parameters.push(part_count);
+
+    write_segment(bytes, SOT, &parameters);
+    write_u16(bytes, SOD);
+    bytes.extend_from_slice(data);
+}
+
+/*
+    JPEG 2000 encoder with the wavelet W (LeGall53 for reversible coding, Cdf97 for irreversible coding):
+    - the size of the tiles (a single tile by default), each tile being coded on its own, and the
+      offsets of the image and of the first tile on the reference grid ((0, 0) by default, the first
+      tiles being cut by the image otherwise; the first tile must hold the top left corner of the image),
+    - the number of decomposition levels (5 by default),
+    - whether the colour components are decorrelated with the component transform (RCT for a
+      reversible wavelet, ICT otherwise), true by default,
+    - the chroma subsampling (none by default) and the filter of the downsampling. As the component
+      transform needs components of the same size, subsampled images are coded as YCbCr (sYCC)
+      components without it,
+    - the quantizer (reversible for a reversible wavelet, step size 1 in the expounded style otherwise),
+    - the tier-1 coder, with the code-block size and style,
+    - the size of the precincts of each resolution level from the lowest one, the last size going on
+      for the higher levels (default precincts, as large as possible, without sizes),
+    - the targets of the quality layers (a single layer with all the coding passes by default),
+    - the limit of the size of the output (.j2k or .jp2 file), none by default: the coding passes
+      which do not fit are left out, starting with the ones bringing the least quality per byte,
+    - the progression order of the packets (LRCP by default), and the progressions coming first
+      if the order changes (written in a POC marker segment; the progression order then sends the
+      remaining packets),
+    - the display resolution of the image, written in JP2 files if it is known.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoder<W> where W: Wavelet {
+    tile_size: Option<(u32, u32)>,
+    image_offset: (u32, u32),
+    tile_origin: (u32, u32),
+    level_count: usize,
+    component_transform: bool,
+    subsampling: ChromaSubsampling,
+    downsampling_filter: DownsamplingFilter,
+    quantizer: Quantizer,
+    block_coder: BlockCoder,
+    precinct_sizes: Vec<PrecinctSize>,
+    layer_targets: Vec<LayerTarget>,
+    size_limit: Option<SizeLimit>,
+    progression_order: ProgressionOrder,
+    progression_changes: Vec<Progression>,
+    resolution: Option<Resolution>,
+    _phantom: PhantomData<W>,
+}
+
+impl<W> Default for Encoder<W> where W: Wavelet {
+    fn default() -> Self { Self::new() }
+}
+
+impl<W> Encoder<W> where W: Wavelet {
+    pub fn new() -> Self {
+        let quantizer = if W::REVERSIBLE { Quantizer::reversible(PRECISION) } else { Quantizer::new(PRECISION, 1.) };
+        Encoder {
+            tile_size: None,
+            image_offset: (0, 0),
+            tile_origin: (0, 0),
+            level_count: 5,
+            component_transform: true,
+            subsampling: ChromaSubsampling::Yuv444,
+            downsampling_filter: DownsamplingFilter::LowPass,
+            quantizer,
+            block_coder: BlockCoder::new(),
+            precinct_sizes: Vec::new(),
+            layer_targets: Vec::new(),
+            size_limit: None,
+            progression_order: ProgressionOrder::Lrcp,
+            progression_changes: Vec::new(),
+            resolution: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_tile_size(&mut self, tile_size: Option<(u32, u32)>) {
+        if tile_size.is_some_and(|(width, height)| width == 0 || height == 0) {
+            panic!("The tiles can not be empty.");
+        }
+        self.tile_size = tile_size;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_image_offset(&mut self, offset: (u32, u32)) { self.image_offset = offset; }
+
+    #[allow(dead_code)]
+    pub fn set_tile_origin(&mut self, origin: (u32, u32)) { self.tile_origin = origin; }
+
+    #[allow(dead_code)]
+    pub fn set_level_count(&mut self, level_count: usize) {
+        if level_count > 32 {
+            panic!("A codestream can not have more than 32 decomposition levels.");
+        }
+        self.level_count = level_count;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_component_transform(&mut self, component_transform: bool) { self.component_transform = component_transform; }
+    #[allow(dead_code)]
+    pub fn set_subsampling(&mut self, subsampling: ChromaSubsampling) { self.subsampling = subsampling; }
+    #[allow(dead_code)]
+    pub fn set_downsampling_filter(&mut self, filter: DownsamplingFilter) { self.downsampling_filter = filter; }
+
+    #[allow(dead_code)]
+    pub fn set_quantizer(&mut self, quantizer: Quantizer) {
+        if quantizer.get_precision() != PRECISION {
+            panic!("The quantizer must be for {} bit components.", PRECISION);
+        }
+        self.quantizer = quantizer;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_block_coder(&mut self, block_coder: BlockCoder) { self.block_coder = block_coder; }
+
+    /*
+        Set the exponents of the width and height of the precincts of the resolution levels, from 1
+        to 15 (32768 samples).
+    */
+    #[allow(dead_code)]
+    pub fn set_precinct_sizes(&mut self, sizes: Vec<PrecinctSize>) {
+        if sizes.iter().any(|&(width, height)| !(1..=15).contains(&width) || !(1..=15).contains(&height)) {
+            panic!("The exponents of the precinct sizes must be between 1 and 15.");
+        }
+        self.precinct_sizes = sizes;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_progression_order(&mut self, order: ProgressionOrder) { self.progression_order = order; }
+
+    /*
+        Set the targets of the quality layers, with increasing rates or PSNRs (all the passes go
+        in a single layer without targets).
+    */
+    #[allow(dead_code)]
+    pub fn set_layer_targets(&mut self, targets: Vec<LayerTarget>) {
+        if targets.len() > u16::MAX as usize {
+            panic!("A codestream can not have more than 65535 layers.");
+        }
+        let increasing = targets.windows(2).all(|pair| match (pair[0], pair[1]) {
+            (LayerTarget::BitsPerPixel(a), LayerTarget::BitsPerPixel(b)) | (LayerTarget::Psnr(a), LayerTarget::Psnr(b)) => a < b,
+            _ => true,
+        });
+        if !increasing {
+            panic!("The targets of the layers must increase.");
+        }
+        self.layer_targets = targets;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_size_limit(&mut self, limit: Option<SizeLimit>) {
+        if let Some(SizeLimit::BitsPerPixel(rate)) = limit {
+            if !(rate > 0. && rate.is_finite()) {
+                panic!("The rate of a size limit must be positive, not {}.", rate);
+            }
+        }
+        self.size_limit = limit;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_progression_changes(&mut self, progressions: Vec<Progression>) {
+        if progressions.iter().any(|progression| progression.get_resolution_end() > 33 || progression.get_layer_end() > u16::MAX as usize) {
+            panic!("A progression can not go beyond 33 resolutions and 65535 layers.");
+        }
+        self.progression_changes = progressions;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_resolution(&mut self, resolution: Option<Resolution>) { self.resolution = resolution; }
+
+    /*
+        Get the components of the codestream of an image: 8 bit unsigned samples, the second and third
+        ones being subsampled.
+    */
+    fn get_components<P: PixelTrait + ConvertPixel>(&self) -> Vec<Component> {
+        let count = if P::CHANNEL_COUNT == 4 { 4 } else { 3 };
+        let (dx, dy) = self.subsampling.get_factors();
+        (0..count).map(|i| match i {
+            1 | 2 => Component::new(PRECISION, false, dx as u8, dy as u8),
+            _ => Component::new(PRECISION, false, 1, 1),
+        }).collect()
+    }
+
+    /*
+        Whether the chroma components are subsampled.
+    */
+    fn is_subsampled(&self) -> bool {
+        self.subsampling != ChromaSubsampling::Yuv444
+    }
+
+    /*
+        Get the weight of the distortion of each subband of the coded components: the square of its
+        step size times its synthesis norm, times the gain of its component through the inverse
+        component transform and its subsampling, so that it is measured on the samples of the image.
+    */
+    fn get_distortion_weights(&self, components: &[Vec<CodedSubband>]) -> Vec<Vec<f64>> {
+        let (dx, dy) = self.subsampling.get_factors();
+        let gains = match (self.component_transform, self.is_subsampled()) {
+            (_, true) => synthesis_gains(false),
+            (true, false) => synthesis_gains(W::REVERSIBLE),
+            (false, false) => [1.; 3],
+        };
+
+        components.iter().enumerate().map(|(component, subbands)| {
+            let gain = match component {
+                0 => gains[0],
+                1 | 2 => gains[component] * (dx * dy) as f64,
+                _ => 1.,
+            };
+            subbands.iter().map(|subband| {
+                let orientation = subband.get_orientation();
+                let level = subband.get_level();
+                let step = subband.get_step_size().get_value(dynamic_range(PRECISION, orientation));
+                let norm = synthesis_norm::<W>(level, orientation.get_x() == 1) * synthesis_norm::<W>(level, orientation.get_y() == 1);
+                gain * (step as f64 * norm as f64).powi(2)
+            }).collect()
+        }).collect()
+    }
+
+    /*
+        Get the size of an image with its tiles.
+    */
+    fn get_size<P: PixelTrait + ConvertPixel>(&self, image: &Image<P>) -> ImageAndTileSize {
+        let (width, height) = (image.get_width(), image.get_height());
+        let ((x0, y0), (tx0, ty0)) = (self.image_offset, self.tile_origin);
+        let tile_size = self.tile_size.unwrap_or(((x0 + width).saturating_sub(tx0), (y0 + height).saturating_sub(ty0)));
+
+        // The tiles of the subsampled components must line up with the ones of the image
+        let (dx, dy) = self.subsampling.get_factors();
+        let (tile_width, tile_height) = if self.tile_size.is_some() { tile_size } else { (dx as u32, dy as u32) };
+        if [x0, tx0, tile_width].iter().any(|&value| value % dx as u32 != 0) || [y0, ty0, tile_height].iter().any(|&value| value % dy as u32 != 0) {
+            panic!("The offsets of the image and of the tiles and the size of the tiles must be multiples of the subsampling factors.");
+        }
+
+        ImageAndTileSize::tiled(width, height, (x0, y0), (tx0, ty0), tile_size, self.get_components::<P>())
+    }
+
+    /*
+        Code a tile of the components of an image: decomposition, quantization and tier-1 coding of
+        each tile-component, from its origin on the grid of its component, with the tier-1 coder of
+        each subband.
+    */
+    fn code_tile(&self, components: &[DMatrix<f32>], size: &ImageAndTileSize, tile: usize, block_coders: &[BlockCoder]) -> Vec<Vec<CodedSubband>> {
+        components.iter().enumerate().map(|(index, component)| {
+            let ((x0, y0), (x1, y1)) = size.get_tile_component_bounds(tile, index);
+            let ((component_x0, component_y0), _) = size.get_component_bounds(index);
+            let start = ((y0 - component_y0) as usize, (x0 - component_x0) as usize);
+            let samples = component.slice(start, ((y1 - y0) as usize, (x1 - x0) as usize)).into_owned();
+            let decomposition = Decomposition::<W>::new(&samples, (x0, y0), self.level_count);
+            self.quantizer.quantize(&decomposition).iter().zip(block_coders).map(|(subband, block_coder)| block_coder.encode_subband(subband)).collect()
+        }).collect()
+    }
+
+    /*
+        Encode an image into a JPEG 2000 codestream.
+    */
+    pub fn encode<P: PixelTrait + ConvertPixel>(&self, image: &Image<P>) -> Vec<u8> {
+        self.encode_codestream(image, 0)
+    }
+
+    /*
+        Encode an image into a JPEG 2000 codestream, which will be wrapped in the given number of
+        bytes counting towards the size limit.
+    */
+    fn encode_codestream<P: PixelTrait + ConvertPixel>(&self, image: &Image<P>, wrapping: usize) -> Vec<u8> {
+        let transform_code = W::TRANSFORM_CODE.unwrap_or_else(|| panic!("This wavelet can not be used in a JPEG 2000 codestream."));
+        let mut components = match (self.component_transform, W::REVERSIBLE) {
+            // The YCbCr components of sYCC
+            _ if self.is_subsampled() => image.to_ict(),
+            (false, _) => image.to_components(),
+            (true, true) => image.to_rct(),
+            (true, false) => image.to_ict(),
+        };
+        let (dx, dy) = self.subsampling.get_factors();
+        for component in components[1..3].iter_mut() {
+            // The samples of the components are co-sited on the reference grid
+            *component = downsample(component, dx, dy, self.downsampling_filter, ChromaSiting::CoSited);
+        }
+        if self.is_subsampled() && W::REVERSIBLE {
+            // The reversible wavelet needs integer samples
+            components.iter_mut().for_each(|component| component.iter_mut().for_each(|sample| *sample = sample.round()));
+        }
+        let size = self.get_size(image);
+        let component_count = components.len();
+        let layer_count = self.layer_targets.len().max(1);
+        let precinct_sizes = match self.precinct_sizes.last() {
+            Some(&last) => (0..=self.level_count).map(|resolution| self.precinct_sizes.get(resolution).copied().unwrap_or(last)).collect(),
+            None => Vec::new(),
+        };
+        let coding_style = CodingStyle::new(
+            self.progression_order,
+            layer_count as u16,
+            self.component_transform && !self.is_subsampled(),
+            self.level_count as u8,
+            &self.block_coder,
+            transform_code,
+            precinct_sizes,
+        );
+
+        // The coded components of all the tiles, one tile after the other
+        let block_coders = coding_style.get_subband_block_coders();
+        let mut coded: Vec<Vec<CodedSubband>> = (0..size.get_tile_count()).flat_map(|tile| self.code_tile(&components, &size, tile, &block_coders)).collect();
+
+        let quantization = QuantizationDefault::new(
+            self.quantizer.get_style(),
+            self.quantizer.get_guard_bits(),
+            self.quantizer.get_signalled_step_sizes::<W>(self.level_count),
+        );
+
+        let mut bytes = Vec::new();
+        write_u16(&mut bytes, SOC);
+        size.write(&mut bytes);
+        coding_style.write(&mut bytes);
+        quantization.write(&mut bytes);
+
+        // The progression order sends the packets which the changes do not
+        let mut progressions = self.progression_changes.clone();
+        progressions.push(Progression::whole(self.progression_order, layer_count, self.level_count + 1, component_count));
+        if progressions.len() > 1 {
+            ProgressionOrderChange::new(progressions.clone()).write(&mut bytes, component_count);
+        }
+        let factors: Vec<(u32, u32)> = size.get_components().iter().map(|component| (component.get_dx() as u32, component.get_dy() as u32)).collect();
+        let layouts: Vec<PacketLayout> = (0..size.get_tile_count()).map(|tile| {
+            PacketLayout::new(progressions.clone(), coding_style.get_precinct_sizes().clone(), size.get_tile_bounds(tile).0, factors.clone())
+        }).collect();
+
+        if !self.layer_targets.is_empty() || self.size_limit.is_some() {
+            // Each tile-part adds SOT and SOD (14 bytes) to its packets, and the codestream ends with EOC (2 bytes)
+            let header_length = bytes.len() + 2;
+            let codestream_length = |coded: &[Vec<CodedSubband>], layer_count| {
+                header_length + coded.chunks(component_count).zip(&layouts).map(|(tile, layout)| 14 + encode_packets(tile, layer_count, layout).len()).sum::<usize>()
+            };
+            let pixel_count = (image.get_width() * image.get_height()) as usize;
+            let weights: Vec<Vec<f64>> = coded.chunks(component_count).flat_map(|tile| self.get_distortion_weights(tile)).collect();
+            let points = TruncationPoints::new(&coded, &weights);
+
+            let thresholds = match self.layer_targets.is_empty() {
+                // All the passes in a single layer
+                true => vec![0.],
+                false => allocate_layers(
+                    &mut coded,
+                    &points,
+                    &self.layer_targets,
+                    pixel_count,
+                    pixel_count * component_count,
+                    ((1_u64 << PRECISION) - 1) as f64,
+                    codestream_length,
+                ),
+            };
+            if let Some(limit) = self.size_limit {
+                let budget = limit.get_budget(pixel_count).checked_sub(wrapping)
+                    .unwrap_or_else(|| panic!("The size limit is smaller than the {} bytes of the JP2 boxes.", wrapping));
+                limit_layers(&mut coded, &points, &thresholds, budget, codestream_length);
+            }
+        }
+
+        for (index, (tile, layout)) in coded.chunks(component_count).zip(&layouts).enumerate() {
+            write_tile_part(&mut bytes, index as u16, 0, 1, &encode_packets(tile, layer_count, layout));
+        }
+        write_u16(&mut bytes, EOC);
+
+        bytes
+    }
+
+    /*
+        Encode an image into a JP2 file (sRGB colour space, or sYCC with a chroma subsampling, with an
+        alpha channel for 4 channel pixels).
+    */
+    pub fn encode_jp2<P: PixelTrait + ConvertPixel>(&self, image: &Image<P>) -> Vec<u8> {
+        let colour_space = if self.is_subsampled() { ColourSpace::Sycc } else { ColourSpace::Srgb };
+        let mut header = Jp2Header::new(image.get_width(), image.get_height(), self.get_components::<P>(), colour_space);
+        header.set_display_resolution(self.resolution);
+
+        // The boxes around the codestream count towards the size limit
+        let wrapping = write_jp2(&header, &[]).len();
+        write_jp2(&header, &self.encode_codestream(image, wrapping))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Rgba;
+    use crate::wavelet::{Cdf97, LeGall53};
+
+    fn components() -> Vec<Component> {
+        vec![Component::new(8, false, 1, 1); 3]
+    }
+
+    #[test]
+    fn tile_origin() {
+        let size = ImageAndTileSize::tiled(40, 27, (5, 3), (2, 1), (16, 16), components());
+        assert_eq!(size.get_tile_counts(), (3, 2));
+        assert_eq!(size.get_tile_bounds(0), ((5, 3), (18, 17)));
+        assert_eq!(size.get_tile_bounds(5), ((34, 17), (45, 30)));
+
+        // The tile origin is written in SIZ
+        let mut bytes = Vec::new();
+        size.write(&mut bytes);
+        assert_eq!(ImageAndTileSize::read(&bytes[4..]), size);
+    }
+
+    #[test]
+    #[should_panic(expected = "The first tile must hold the top left corner of the image.")]
+    fn tile_origin_after_image() {
+        ImageAndTileSize::tiled(40, 27, (5, 3), (6, 0), (16, 16), components());
+    }
+
+    #[test]
+    #[should_panic(expected = "The first tile must hold the top left corner of the image.")]
+    fn tile_origin_before_first_tile() {
+        ImageAndTileSize::tiled(40, 27, (16, 3), (0, 0), (16, 16), components());
+    }
+
+    #[test]
+    #[should_panic(expected = "An image can not have more than 65535 tiles.")]
+    fn too_many_tiles() {
+        ImageAndTileSize::tiled(256, 257, (0, 0), (0, 0), (1, 1), components());
+    }
+
+    /*
+        Image of 48x40 pixels with gradients and pseudo-random noise (from a linear congruential generator).
+    */
+    fn image() -> Image<Rgba<u8>> {
+        let mut state = 7_u32;
+        Image::from_rgba_matrix(&DMatrix::from_fn(40, 48, |y, x| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let noise = (state >> 24) as usize % 32;
+            Rgba::new((x * 5 + noise) as u8, (y * 6 + noise) as u8, ((x + y) * 2) as u8, 255)
+        }))
+    }
+
+    /*
+        Check that the outputs of an encoder with size limits, as .j2k and .jp2 files, are within the
+        limits and close to them, the coding passes left out being small.
+    */
+    fn check_size_limits<W: Wavelet>(mut encoder: Encoder<W>) {
+        let image = image();
+        let limits = [SizeLimit::Bytes(400), SizeLimit::Bytes(1500), SizeLimit::BitsPerPixel(2.5), SizeLimit::BitsPerPixel(10.)];
+        for limit in limits {
+            encoder.set_size_limit(Some(limit));
+            let budget = limit.get_budget(48 * 40);
+            for length in [encoder.encode(&image).len(), encoder.encode_jp2(&image).len()] {
+                assert!(length <= budget && length * 10 >= budget * 9, "{} bytes for a limit of {}", length, budget);
+            }
+        }
+    }
+
+    #[test]
+    fn size_limits() {
+        check_size_limits(Encoder::<LeGall53>::new());
+        check_size_limits(Encoder::<Cdf97>::new());
+
+        let mut layered = Encoder::<Cdf97>::new();
+        layered.set_layer_targets(vec![LayerTarget::BitsPerPixel(0.5), LayerTarget::BitsPerPixel(2.), LayerTarget::BitsPerPixel(20.)]);
+        check_size_limits(layered);
+    }
+}
@@ -0,0 +1,246 @@
+use nalgebra::{DMatrix, Matrix3, Vector3};
+use crate::conversion::ConvertPixel;
+use crate::my_image::Image;
+use crate::pixel_type::PixelTrait;
+
+/*
+    Crate of the DC level shift and of the multiple component transforms of JPEG 2000 (Annex G of the spec).
+
+    The samples of the unsigned components are first shifted by 2^(B-1) (B being their precision) so
+    that they are centered on 0. Then the first three components (red, green and blue) can be
+    decorrelated with:
+    - the reversible component transform (RCT), with integer operations, for lossless coding with the
+      LeGall53 wavelet,
+    - the irreversible component transform (ICT), the YCbCr transform of ITU-R BT.601, for lossy coding
+      with the Cdf97 wavelet.
+    The other components (alpha) are kept as they are.
+*/
+
+// Coefficients of the ICT (Y, Cb and Cr from R, G and B) and of its inverse
+const ICT: [f32; 9] = [
+    0.299, 0.587, 0.114,
+    -0.168736, -0.331264, 0.5,
+    0.5, -0.418688, -0.081312,
+];
+const INVERSE_ICT: [f32; 9] = [
+    1.0, 0.0, 1.402,
+    1.0, -0.344136, -0.714136,
+    1.0, 1.772, 0.0,
+];
+
+/*
+    Shift the samples of an unsigned component with the given precision to center them on 0.
+*/
+pub fn dc_level_shift(component: &mut DMatrix<f32>, precision: u8) {
+    component.add_scalar_mut(-((1_u64 << (precision - 1)) as f32));
+}
+
+/*
+    Shift back the samples of an unsigned component with the given precision.
+*/
+pub fn inverse_dc_level_shift(component: &mut DMatrix<f32>, precision: u8) {
+    component.add_scalar_mut((1_u64 << (precision - 1)) as f32);
+}
+
+/*
+    Check that the first three components can be transformed together.
+*/
+fn check_components(components: &[DMatrix<f32>]) {
+    if components.len() < 3 {
+        panic!("A component transform needs 3 components, not {}.", components.len());
+    }
+    if components[1].shape() != components[0].shape() || components[2].shape() != components[0].shape() {
+        panic!("The components of a component transform must have the same size.");
+    }
+}
+
+/*
+    Apply a 3x3 matrix to the first three components, sample by sample.
+*/
+fn apply_matrix(components: &mut [DMatrix<f32>], matrix: &Matrix3<f32>) {
+    check_components(components);
+
+    for i in 0..components[0].len() {
+        let samples = matrix * Vector3::new(components[0][i], components[1][i], components[2][i]);
+        for (component, sample) in components.iter_mut().zip(samples.iter()) {
+            component[i] = *sample;
+        }
+    }
+}
+
+/*
+    Reversible component transform (G.2) of the first three components (with integer samples):
+    Y = floor((R + 2G + B) / 4), Db = B - G and Dr = R - G.
+*/
+pub fn forward_rct(components: &mut [DMatrix<f32>]) {
+    check_components(components);
+
+    for i in 0..components[0].len() {
+        let (r, g, b) = (components[0][i], components[1][i], components[2][i]);
+        components[0][i] = ((r + 2. * g + b) / 4.).floor();
+        components[1][i] = b - g;
+        components[2][i] = r - g;
+    }
+}
+
+/*
+    Inverse of the reversible component transform:
+    G = Y - floor((Db + Dr) / 4), R = Dr + G and B = Db + G.
+*/
+pub fn inverse_rct(components: &mut [DMatrix<f32>]) {
+    check_components(components);
+
+    for i in 0..components[0].len() {
+        let (y, db, dr) = (components[0][i], components[1][i], components[2][i]);
+        let g = y - ((db + dr) / 4.).floor();
+        components[0][i] = dr + g;
+        components[1][i] = g;
+        components[2][i] = db + g;
+    }
+}
+
+/*
+    Irreversible component transform (G.3) of the first three components.
+*/
+pub fn forward_ict(components: &mut [DMatrix<f32>]) {
+    apply_matrix(components, &Matrix3::from_row_slice(&ICT));
+}
+
+/*
+    Inverse of the irreversible component transform.
+*/
+pub fn inverse_ict(components: &mut [DMatrix<f32>]) {
+    apply_matrix(components, &Matrix3::from_row_slice(&INVERSE_ICT));
+}
+
+/*
+    Get the energy gain of an error on each of the first three components through the inverse
+    component transform (reversible or irreversible): the sum of the squares of its weights in
+    the red, green and blue samples.
+*/
+pub fn synthesis_gains(reversible: bool) -> [f64; 3] {
+    match reversible {
+        // G = Y - (Db + Dr) / 4, R = G + Dr and B = G + Db (without the rounding)
+        true => [3., 11. / 16., 11. / 16.],
+        false => {
+            let matrix = Matrix3::from_row_slice(&INVERSE_ICT);
+            [0, 1, 2].map(|i| matrix.column(i).norm_squared() as f64)
+        },
+    }
+}
+
+/*
+    Component transforms between an image and its 8 bit components (red, green, blue, and alpha
+    for 4 channel pixels), DC level shifted.
+*/
+pub trait ComponentTransform {
+    fn to_components(&self) -> Vec<DMatrix<f32>>;
+    fn to_rct(&self) -> Vec<DMatrix<f32>>;
+    fn to_ict(&self) -> Vec<DMatrix<f32>>;
+}
+
+impl<P: PixelTrait + ConvertPixel> ComponentTransform for Image<P> {
+    fn to_components(&self) -> Vec<DMatrix<f32>> {
+        let count = if P::CHANNEL_COUNT == 4 { 4 } else { 3 };
+        let (width, height) = (self.get_width() as usize, self.get_height() as usize);
+
+        (0..count).map(|channel| {
+            let mut component = DMatrix::from_fn(height, width, |y, x| {
+                let pixel = self.get_pixel(x as u32, y as u32).to_rgba();
+                [pixel.get_red(), pixel.get_green(), pixel.get_blue(), pixel.get_alpha()][channel] as f32
+            });
+            dc_level_shift(&mut component, 8);
+            component
+        }).collect()
+    }
+
+    fn to_rct(&self) -> Vec<DMatrix<f32>> {
+        let mut components = self.to_components();
+        forward_rct(&mut components);
+        components
+    }
+
+    fn to_ict(&self) -> Vec<DMatrix<f32>> {
+        let mut components = self.to_components();
+        forward_ict(&mut components);
+        components
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+        Components of one line holding the given red, green and blue samples.
+    */
+    fn components(samples: &[(f32, f32, f32)]) -> Vec<DMatrix<f32>> {
+        vec![
+            DMatrix::from_iterator(1, samples.len(), samples.iter().map(|s| s.0)),
+            DMatrix::from_iterator(1, samples.len(), samples.iter().map(|s| s.1)),
+            DMatrix::from_iterator(1, samples.len(), samples.iter().map(|s| s.2)),
+        ]
+    }
+
+    /*
+        DC level shifted 8 bit colors, on a grid covering -128..=127 on each channel.
+    */
+    fn shifted_colors() -> Vec<(f32, f32, f32)> {
+        let values: Vec<f32> = (0..=255).step_by(5).chain([254]).map(|v| v as f32 - 128.).collect();
+        let mut colors = Vec::new();
+        for &r in &values {
+            for &g in &values {
+                for &b in &values {
+                    colors.push((r, g, b));
+                }
+            }
+        }
+        colors
+    }
+
+    #[test]
+    fn rct_is_exact() {
+        let original = components(&shifted_colors());
+        let mut transformed = original.clone();
+        forward_rct(&mut transformed);
+
+        // Db and Dr take their whole range, negative values included
+        for difference in &transformed[1..] {
+            assert_eq!((difference.min(), difference.max()), (-255., 255.));
+        }
+        assert_eq!((transformed[0].min(), transformed[0].max()), (-128., 127.));
+
+        inverse_rct(&mut transformed);
+        assert_eq!(transformed, original);
+    }
+
+    #[test]
+    fn ict_round_trip() {
+        let original = components(&shifted_colors());
+        let mut transformed = original.clone();
+        forward_ict(&mut transformed);
+        inverse_ict(&mut transformed);
+
+        for (rebuilt, component) in transformed.iter().zip(&original) {
+            let error = (rebuilt - component).amax();
+            assert!(error < 0.01, "error {}", error);
+            assert_eq!(rebuilt.map(|sample| sample.round()), *component);
+        }
+    }
+
+    #[test]
+    fn rct_synthesis_gains() {
+        // Multiple of 4 so that the floor of the inverse RCT is exact
+        const AMPLITUDE: f32 = 4096.;
+
+        let gains = synthesis_gains(true);
+        for (component, gain) in gains.iter().enumerate() {
+            let mut impulse = components(&[(0., 0., 0.)]);
+            impulse[component][0] = AMPLITUDE;
+            inverse_rct(&mut impulse);
+
+            let energy: f32 = impulse.iter().map(|samples| samples[0] * samples[0]).sum();
+            assert_eq!((energy / (AMPLITUDE * AMPLITUDE)) as f64, *gain, "component {}", component);
+        }
+    }
+}
@@ -1,115 +1,122 @@
-use std::marker::PhantomData;
-use nalgebra::Matrix2;
+use nalgebra::DMatrix;
 use crate::color::Yuv;
 use crate::my_image::Image;
-use crate::pixel_type::PixelTrait;
+use crate::subband::Decomposition;
+use crate::wavelet::{Wavelet, forward_2d, inverse_2d};
 
-pub struct Superpixel<P> where P: PixelTrait {
-    pixels: Matrix2<f32>,
-    _phantom: PhantomData<P>,
-}
-
-impl<P> Superpixel<P> where P: PixelTrait {
-    pub fn new(pixels: Matrix2<f32>) -> Self {
-        Superpixel {pixels, _phantom: PhantomData}
-    }
+#[allow(dead_code)]
+pub trait Compress {
+    fn compress<W: Wavelet>(&self, channel: usize) -> Self;
 }
 
-pub trait Calculate {
-    const TL: (usize, usize) = (0, 0);
-    const TR: (usize, usize) = (0, 1);
-    const BL: (usize, usize) = (1, 0);
-    const BR: (usize, usize) = (1, 1);
+impl Compress for Image<Yuv<f32>> {
+    /*
+        Return the image with the given channel replaced by its subbands, in the standard layout:
 
-    fn horizontal_diff(&self) -> f32;
+                 LL | HL
+                ----+----
+                 LH | HH
 
-    fn vertical_diff(&self) -> f32;
+        - LL (top left) holds the lowpass coefficients in both directions,
+        - HL (top right) holds the horizontal details (highpass on the rows),
+        - LH (bottom left) holds the vertical details (highpass on the columns),
+        - HH (bottom right) holds the diagonal details.
 
-    fn diagonal_diff(&self) -> f32;
+        The other channels are copied as they are, so each channel can be compressed in turn.
+    */
+    fn compress<W: Wavelet>(&self, channel: usize) -> Self {
+        let mut new_image = self.clone();
+        let subbands = forward_2d::<W>(&self.get_channel(channel), (0, 0));
+        new_image.set_channel_matrix(channel, &subbands);
 
-    fn average(&self) -> f32;
+        new_image
+    }
 }
 
-/*
-    The four pixels of the superpixel are arranged as follows:
-    
-                                  _  ----------------------------  _
-                                 |  |  tl  \  tr  | -tl  \ -tr  |  |
-                     Average -> |  -----------------------------  | <- Vertical difference 
-                               |_ |  bl  \  br  | +bl  \ +br  | _|
-                               _  ----------------------------  _
-                              |  | -tl  \ +tr  | +tl  \ -tr  |  |
-    Horizontal difference -> |  -----------------------------  | <- Diagonal difference
-                            |_ | -bl  \ +br  | -bl  \ +br  | _|
-                               ----------------------------
-*/
-impl<P> Calculate for Superpixel<P> where P: PixelTrait {
-    fn horizontal_diff(&self) -> f32 {
-        let pixels = self.pixels.clone();
-
-        -pixels[Self::TL] + pixels[Self::TR] - pixels[Self::BL] + pixels[Self::BR]
-    }
+#[allow(dead_code)]
+pub trait Decompress {
+    fn decompress<W: Wavelet>(&self, channel: usize) -> Self;
+}
 
-    fn vertical_diff(&self) -> f32 {
-        let pixels = self.pixels.clone();
+impl Decompress for Image<Yuv<f32>> {
+    /*
+        Return the image with the given channel rebuilt from its subbands (inverse of compress).
+        The other channels are copied as they are.
+    */
+    fn decompress<W: Wavelet>(&self, channel: usize) -> Self {
+        let mut new_image = self.clone();
+        let pixels = inverse_2d::<W>(&self.get_channel(channel), (0, 0));
+        new_image.set_channel_matrix(channel, &pixels);
 
-        -pixels[Self::TL] - pixels[Self::TR] + pixels[Self::BL] + pixels[Self::BR]
+        new_image
     }
+}
 
-    fn diagonal_diff(&self) -> f32 {
-        let pixels = self.pixels.clone();
-
-        pixels[Self::TL] - pixels[Self::TR] - pixels[Self::BL] + pixels[Self::BR]
+impl Image<Yuv<f32>> {
+    /*
+        Decompose each channel of the image with the wavelet W on the given number of levels.
+    */
+    pub fn decompose<W: Wavelet>(&self, level_count: usize) -> Vec<Decomposition<W>> {
+        (0..3).map(|channel| Decomposition::new(&self.get_channel(channel), (0, 0), level_count)).collect()
     }
 
-    fn average(&self) -> f32 {
-        let pixels = self.pixels.clone();
-
-        (pixels[Self::TL] + pixels[Self::TR] + pixels[Self::BL] + pixels[Self::BR]) / 4.
+    /*
+        Rebuild the image from the decompositions of its channels.
+    */
+    pub fn from_decompositions<W: Wavelet>(decompositions: &[Decomposition<W>]) -> Self {
+        let channels: Vec<DMatrix<f32>> = decompositions.iter().map(|decomposition| decomposition.reconstruct()).collect();
+        Image::from_channel_matrices(&channels)
     }
 }
 
-pub trait Compress {
-    fn compress(&self, channel: usize) -> Self;
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Rgb;
+    use crate::conversion::ConvertImage;
+    use crate::wavelet::Haar;
 
-impl Compress for Superpixel<Yuv<f32>> {
     /*
-        Return the new superpixel with the compressed values.
-        The first (top left) pixel is the average of the four pixels of the superpixel.
-        The second (top right) pixel is the vertical difference between the four pixels of the superpixel.
-        The third (bottom left) pixel is the horizontal difference between the four pixels of the superpixel.
-        The fourth (bottom right) pixel is the diagonal difference between the four pixels of the superpixel.
+        Image of pseudo-random colors (from a linear congruential generator), with an odd width.
     */
-    fn compress(&self, _channel: usize) -> Self {
-        let horizontal = self.horizontal_diff();
-        let vertical = self.vertical_diff();
-        let diagonal = self.diagonal_diff();
-        let average = self.average();
-
-        let new_pixels = Matrix2::new(average, vertical, horizontal, diagonal);
-
-        Superpixel::new(new_pixels)
+    fn image() -> Image<Rgb<u8>> {
+        let mut state = 3_u32;
+        let mut next = || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        };
+        let mut image = Image::new(9, 6);
+        for y in 0..6 {
+            for x in 0..9 {
+                image.set_pixel(x, y, &[next(), next(), next()]);
+            }
+        }
+        image
     }
-}
 
-impl Compress for Image<Yuv<f32>> {
-    fn compress(&self, channel: usize) -> Self {
-        let mut new_image = Image::new(self.get_width(), self.get_height());
-        let superpixels = self.get_superpixels(channel);
-
-        // Compress each superpixel
-        let mut compressed_superpixels: Vec<Superpixel<Yuv<f32>>> = Vec::new();
-        for superpixel in superpixels {
-            let compressed = superpixel.compress(channel);
-            compressed_superpixels.push(compressed);
+    #[test]
+    fn haar_round_trip() {
+        let image = image();
+        let yuv = image.to_yuv();
+
+        let mut subbands = yuv.clone();
+        for channel in 0..3 {
+            subbands = subbands.compress::<Haar>(channel);
         }
-        
-        // Rebuid the image with the compressed superpixels
-        for (i, superpixel) in compressed_superpixels.iter().enumerate() {
-            todo!("Rebuild the image with the compressed superpixels");
+
+        // The top left coefficient of LL is the average of the first 2x2 block
+        for channel in 0..3 {
+            let block = yuv.get_channel(channel).slice((0, 0), (2, 2)).sum() / 4.;
+            assert!((subbands.get_channel(channel)[(0, 0)] - block).abs() < 1e-6);
         }
 
-        new_image
+        let mut rebuilt = subbands;
+        for channel in 0..3 {
+            rebuilt = rebuilt.decompress::<Haar>(channel);
+        }
+        let rebuilt = rebuilt.to_rgb();
+        for channel in 0..3 {
+            assert_eq!(rebuilt.get_channel(channel), image.get_channel(channel));
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,5 @@
 use crate::color::{YCbCr, Rgb, Rgba, Yuv};
-use nalgebra::{Matrix3, Matrix3x1};
+use nalgebra::Matrix3;
 use crate::pixel_type::PixelTrait;
 use crate::my_image::Image;
 
@@ -7,17 +7,8 @@ pub fn rgb2rgba(rgb: Rgb<u8>) -> Rgba<u8> {
     Rgba::<u8>::new(rgb.get_red(), rgb.get_green(), rgb.get_blue(), 255)
 }
 
-pub fn rgb2ycbcr(rgb: Rgb<u8>) -> YCbCr<u8> {
-    let m = Matrix3::new(
-        77, 129, 0.114,
-        -0.168736, -0.331264, 0.5,
-        0.5, -0.418688, -0.081312
-    );
-
-    let mut res = m*rgb.channels().map(|x| x as f64);
-    res += Matrix3x1::new(16., 128., 128.);
-
-    YCbCr::new(res[0] as u8, res[1] as u8, res[2] as u8)
+pub fn rgb2ycbcr(rgb: Rgb<u8>, parameters: &YCbCrParameters) -> YCbCr<u8> {
+    ycbcr_from_channels(rgb.get_red(), rgb.get_green(), rgb.get_blue(), parameters)
 }
 
 pub fn rgb2yuv(rgb: Rgb<u8>) -> Yuv<f32> {
@@ -36,17 +27,8 @@ pub fn rgba2rgb(rgba: Rgba<u8>) -> Rgb<u8> {
     Rgb::<u8>::new(rgba.get_red(), rgba.get_green(), rgba.get_blue())
 }
 
-pub fn rgba2ycbcr(rgba: Rgba<u8>) -> YCbCr<u8> {
-    let m = Matrix3::new(
-        0.299, 0.587, 0.114,
-        -0.168736, -0.331264, 0.5,
-        0.5, -0.418688, -0.081312
-    );
-
-    let mut res = m*rgba.channels().map(|x| x as f64);
-    res += Matrix3x1::new(0., 128., 128.);
-
-    YCbCr::new(res[0] as u8, res[1] as u8, res[2] as u8)
+pub fn rgba2ycbcr(rgba: Rgba<u8>, parameters: &YCbCrParameters) -> YCbCr<u8> {
+    ycbcr_from_channels(rgba.get_red(), rgba.get_green(), rgba.get_blue(), parameters)
 }
 
 pub fn rgba2yuv(rgba: Rgba<u8>) -> Yuv<f32> {
@@ -56,43 +38,39 @@ pub fn rgba2yuv(rgba: Rgba<u8>) -> Yuv<f32> {
         0.615, -0.51498, -0.10001
     );
 
-    let res = m*rgba.channels().map(|x| dc_offset(x) as f32);
+    let res = m*rgba.channels().map(dc_offset);
 
-    Yuv::new(res[0] as f32, res[1] as f32, res[2] as f32)
+    Yuv::new(res[0], res[1], res[2])
 }
 
-pub fn ycbcr2rgb(ycbcr: YCbCr<u8>) -> Rgb<u8> {
-    let y = ycbcr.get_y() as f32;
-    let cb = ycbcr.get_cb() as f32;
-    let cr = ycbcr.get_cr() as f32;
-
-    let r = (y + 1.402*(cr - 128.0)).round() as u8;
-    let g = (y - 0.344136*(cb - 128.0) - 0.714136*(cr - 128.0)).round() as u8;
-    let b = (y + 1.772*(cb - 128.)).round() as u8;
-
+pub fn ycbcr2rgb(ycbcr: YCbCr<u8>, parameters: &YCbCrParameters) -> Rgb<u8> {
+    let (r, g, b) = ycbcr_to_channels(ycbcr, parameters);
     Rgb::<u8>::new(r, g, b)
 }
 
-pub fn ycbcr2rgba(ycbcr: YCbCr<u8>) -> Rgba<u8> {
-    let y = ycbcr.get_y() as f32;
-    let cb = ycbcr.get_cb() as f32;
-    let cr = ycbcr.get_cr() as f32;
-
-    let r = (y + 1.402*(cr - 128.0)).round() as u8;
-    let g = (y - 0.344136*(cb - 128.0) - 0.714136*(cr - 128.0)).round() as u8;
-    let b = (y + 1.772*(cb - 128.)).round() as u8;
+pub fn ycbcr2rgba(ycbcr: YCbCr<u8>, parameters: &YCbCrParameters) -> Rgba<u8> {
+    let (r, g, b) = ycbcr_to_channels(ycbcr, parameters);
+    Rgba::<u8>::new(r, g, b, 255)
+}
 
-    Rgba::<u8>::new(r, g, b,  255)
+/*
+    Convert a YCbCr pixel between two YCbCr standards (through RGB, unless they are the same).
+*/
+pub fn ycbcr2ycbcr(ycbcr: YCbCr<u8>, from: &YCbCrParameters, to: &YCbCrParameters) -> YCbCr<u8> {
+    match from == to {
+        true => ycbcr,
+        false => rgb2ycbcr(ycbcr2rgb(ycbcr, from), to),
+    }
 }
 
-pub fn ycbcr2yuv(ycbcr: YCbCr<u8>) -> Yuv<f32> {
-    let rgb = ycbcr2rgb(ycbcr);
+pub fn ycbcr2yuv(ycbcr: YCbCr<u8>, parameters: &YCbCrParameters) -> Yuv<f32> {
+    let rgb = ycbcr2rgb(ycbcr, parameters);
     rgb2yuv(rgb)
 }
 
-pub fn yuv2ycbcr(yuv: Yuv<f32>) -> YCbCr<u8> {
+pub fn yuv2ycbcr(yuv: Yuv<f32>, parameters: &YCbCrParameters) -> YCbCr<u8> {
     let rgb = yuv2rgb(yuv);
-    rgb2ycbcr(rgb)
+    rgb2ycbcr(rgb, parameters)
 }
 
 pub fn yuv2rgb(yuv: Yuv<f32>) -> Rgb<u8> {
@@ -102,9 +80,9 @@ pub fn yuv2rgb(yuv: Yuv<f32>) -> Rgb<u8> {
         1.0, 2.03211, 0.0
     );
 
-    let res = m*yuv.channels().map(|x| dc_offset_inv(x) as f64);
+    let res = m*yuv.channels().map(dc_offset_inv);
 
-    Rgb::new(res[0] as u8, res[1] as u8, res[2] as u8)
+    Rgb::new(to_u8(res[0]), to_u8(res[1]), to_u8(res[2]))
 }
 
 pub fn yuv2rgba(yuv: Yuv<f32>) -> Rgba<u8> {
@@ -114,83 +92,177 @@ pub fn yuv2rgba(yuv: Yuv<f32>) -> Rgba<u8> {
         1.0, 2.03211, 0.0
     );
 
-    let res = m*yuv.channels().map(|x| dc_offset_inv(x) as f64);
+    let res = m*yuv.channels().map(dc_offset_inv);
+
+    Rgba::new(to_u8(res[0]), to_u8(res[1]), to_u8(res[2]), 255)
+}
+
+/*
+    Matrix coefficients of the YCbCr standards: ITU-R BT.601 (SD television and JPEG),
+    BT.709 (HD television) and BT.2020 (UHD television).
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YCbCrMatrix {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+impl YCbCrMatrix {
+    /*
+        Get the weights of red and blue in the luminance (Kr, Kb), green having the rest.
+    */
+    pub fn get_coefficients(&self) -> (f64, f64) {
+        match self {
+            YCbCrMatrix::Bt601 => (0.299, 0.114),
+            YCbCrMatrix::Bt709 => (0.2126, 0.0722),
+            YCbCrMatrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/*
+    Range of the 8 bit YCbCr values:
+    - full range: Y in 0..255 and Cb, Cr in 0..255 centered on 128 (as in JPEG),
+    - studio (limited) range: Y in 16..235 and Cb, Cr in 16..240 centered on 128 (as in video).
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YCbCrRange {
+    Full,
+    Studio,
+}
+
+impl YCbCrRange {
+    /*
+        Get the offset and the scale of the luminance and of the chroma components.
+    */
+    fn get_scales(&self) -> ((f64, f64), (f64, f64)) {
+        match self {
+            YCbCrRange::Full => ((0., 1.), (128., 1.)),
+            YCbCrRange::Studio => ((16., 219. / 255.), (128., 224. / 255.)),
+        }
+    }
+}
+
+/*
+    Parameters of the conversions between RGB and YCbCr: the matrix coefficients and the range.
+    The default parameters are the ones of JPEG (JFIF): BT.601 with the full range.
+
+    The results are rounded to the nearest integer and clamped to 0..255: converting a RGB color
+    to YCbCr and back changes each channel by at most 1 with the full range, and at most 2 with
+    the studio range which has less values (checked on a sample of the colors for each matrix).
+    YCbCr pixels converted without parameters (to RGB, YUV...) are taken with the default parameters.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YCbCrParameters {
+    matrix: YCbCrMatrix,
+    range: YCbCrRange,
+}
+
+impl Default for YCbCrParameters {
+    fn default() -> Self { YCbCrParameters::new(YCbCrMatrix::Bt601, YCbCrRange::Full) }
+}
+
+impl YCbCrParameters {
+    pub fn new(matrix: YCbCrMatrix, range: YCbCrRange) -> Self {
+        YCbCrParameters {matrix, range}
+    }
+}
+
+fn to_u8(value: f64) -> u8 {
+    value.round().clamp(0., 255.) as u8
+}
+
+fn ycbcr_from_channels(r: u8, g: u8, b: u8, parameters: &YCbCrParameters) -> YCbCr<u8> {
+    let (kr, kb) = parameters.matrix.get_coefficients();
+    let ((y_offset, y_scale), (c_offset, c_scale)) = parameters.range.get_scales();
+    let (r, g, b) = (r as f64, g as f64, b as f64);
 
-    Rgba::new(res[0] as u8, res[1] as u8, res[2] as u8, 255)
+    let y = kr*r + (1. - kr - kb)*g + kb*b;
+    let cb = (b - y) / (2. * (1. - kb));
+    let cr = (r - y) / (2. * (1. - kr));
+
+    YCbCr::new(to_u8(y_offset + y_scale*y), to_u8(c_offset + c_scale*cb), to_u8(c_offset + c_scale*cr))
+}
+
+fn ycbcr_to_channels(ycbcr: YCbCr<u8>, parameters: &YCbCrParameters) -> (u8, u8, u8) {
+    let (kr, kb) = parameters.matrix.get_coefficients();
+    let ((y_offset, y_scale), (c_offset, c_scale)) = parameters.range.get_scales();
+
+    let y = (ycbcr.get_y() as f64 - y_offset) / y_scale;
+    let cb = (ycbcr.get_cb() as f64 - c_offset) / c_scale;
+    let cr = (ycbcr.get_cr() as f64 - c_offset) / c_scale;
+
+    let r = y + 2. * (1. - kr)*cr;
+    let b = y + 2. * (1. - kb)*cb;
+    let g = (y - kr*r - kb*b) / (1. - kr - kb);
+
+    (to_u8(r), to_u8(g), to_u8(b))
 }
 
 fn dc_offset(value: u8) -> f32 {
     value as f32 / 255.
 }
 
-fn dc_offset_inv(value: f32) -> u8 {
-    (value * 255.) as u8
+/*
+    Scale a YUV value back to 0..255, left unrounded until the conversion to RGB is done.
+*/
+fn dc_offset_inv(value: f32) -> f64 {
+    value as f64 * 255.
 }
 
 pub trait ConvertPixel {
+    #[allow(dead_code)]
     fn to_rgb(&self) -> Rgb<u8>;
     fn to_rgba(&self) -> Rgba<u8>;
-    fn to_ycbcr(&self) -> YCbCr<u8>;
+    fn to_ycbcr(&self, parameters: &YCbCrParameters) -> YCbCr<u8>;
     fn to_yuv(&self) -> Yuv<f32>;
 
-    fn from_rgb(rgb: Rgb<u8>) -> Self;
     fn from_rgba(rgba: Rgba<u8>) -> Self;
-    fn from_ycbcr(ycbcr: YCbCr<u8>) -> Self;
-    fn from_yuv(yuv: Yuv<f32>) -> Self;
 }
 
 impl ConvertPixel for Rgb<u8> {
-    fn to_rgb(&self) -> Rgb<u8> { self.clone() }
-    fn to_rgba(&self) -> Rgba<u8> { rgb2rgba(self.clone()) }
-    fn to_ycbcr(&self) -> YCbCr<u8> { rgb2ycbcr(self.clone()) }
-    fn to_yuv(&self) -> Yuv<f32> { rgb2yuv(self.clone()) }
+    fn to_rgb(&self) -> Rgb<u8> { *self }
+    fn to_rgba(&self) -> Rgba<u8> { rgb2rgba(*self) }
+    fn to_ycbcr(&self, parameters: &YCbCrParameters) -> YCbCr<u8> { rgb2ycbcr(*self, parameters) }
+    fn to_yuv(&self) -> Yuv<f32> { rgb2yuv(*self) }
 
-    fn from_rgb(rgb: Rgb<u8>) -> Self { rgb }
     fn from_rgba(rgba: Rgba<u8>) -> Self { rgba2rgb(rgba) }
-    fn from_ycbcr(ycbcr: YCbCr<u8>) -> Self { ycbcr2rgb(ycbcr) }
-    fn from_yuv(yuv: Yuv<f32>) -> Self { yuv2rgb(yuv) }
 }
 
 impl ConvertPixel for Rgba<u8> {
-    fn to_rgb(&self) -> Rgb<u8> { rgba2rgb(self.clone()) }
-    fn to_rgba(&self) -> Rgba<u8> { self.clone() }
-    fn to_ycbcr(&self) -> YCbCr<u8> { rgba2ycbcr(self.clone()) }
-    fn to_yuv(&self) -> Yuv<f32> { rgba2yuv(self.clone()) }
+    fn to_rgb(&self) -> Rgb<u8> { rgba2rgb(*self) }
+    fn to_rgba(&self) -> Rgba<u8> { *self }
+    fn to_ycbcr(&self, parameters: &YCbCrParameters) -> YCbCr<u8> { rgba2ycbcr(*self, parameters) }
+    fn to_yuv(&self) -> Yuv<f32> { rgba2yuv(*self) }
 
-    fn from_rgb(rgb: Rgb<u8>) -> Self { rgb2rgba(rgb) }
     fn from_rgba(rgba: Rgba<u8>) -> Self { rgba }
-    fn from_ycbcr(ycbcr: YCbCr<u8>) -> Self { ycbcr2rgba(ycbcr) }
-    fn from_yuv(yuv: Yuv<f32>) -> Self { yuv2rgba(yuv) }
 }
 
 impl ConvertPixel for YCbCr<u8> {
-    fn to_rgb(&self) -> Rgb<u8> { ycbcr2rgb(self.clone()) }
-    fn to_rgba(&self) -> Rgba<u8> { ycbcr2rgba(self.clone()) }
-    fn to_ycbcr(&self) -> YCbCr<u8> { self.clone() }
-    fn to_yuv(&self) -> Yuv<f32> { ycbcr2yuv(self.clone()) }
+    fn to_rgb(&self) -> Rgb<u8> { ycbcr2rgb(*self, &YCbCrParameters::default()) }
+    fn to_rgba(&self) -> Rgba<u8> { ycbcr2rgba(*self, &YCbCrParameters::default()) }
+    fn to_ycbcr(&self, parameters: &YCbCrParameters) -> YCbCr<u8> { ycbcr2ycbcr(*self, &YCbCrParameters::default(), parameters) }
+    fn to_yuv(&self) -> Yuv<f32> { ycbcr2yuv(*self, &YCbCrParameters::default()) }
 
-    fn from_rgb(rgb: Rgb<u8>) -> Self { rgb2ycbcr(rgb) }
-    fn from_rgba(rgba: Rgba<u8>) -> Self { rgba2ycbcr(rgba) }
-    fn from_ycbcr(ycbcr: YCbCr<u8>) -> Self { ycbcr }
-    fn from_yuv(yuv: Yuv<f32>) -> Self { yuv2ycbcr(yuv) }
+    fn from_rgba(rgba: Rgba<u8>) -> Self { rgba2ycbcr(rgba, &YCbCrParameters::default()) }
 }
 
 impl ConvertPixel for Yuv<f32> {
-    fn to_rgb(&self) -> Rgb<u8> { yuv2rgb(self.clone()) }
-    fn to_rgba(&self) -> Rgba<u8> { yuv2rgba(self.clone()) }
-    fn to_ycbcr(&self) -> YCbCr<u8> { yuv2ycbcr(self.clone()) }
-    fn to_yuv(&self) -> Yuv<f32> { self.clone() }
+    fn to_rgb(&self) -> Rgb<u8> { yuv2rgb(*self) }
+    fn to_rgba(&self) -> Rgba<u8> { yuv2rgba(*self) }
+    fn to_ycbcr(&self, parameters: &YCbCrParameters) -> YCbCr<u8> { yuv2ycbcr(*self, parameters) }
+    fn to_yuv(&self) -> Yuv<f32> { *self }
 
-    fn from_rgb(rgb: Rgb<u8>) -> Self { rgb2yuv(rgb) }
     fn from_rgba(rgba: Rgba<u8>) -> Self { rgba2yuv(rgba) }
-    fn from_ycbcr(ycbcr: YCbCr<u8>) -> Self { ycbcr2yuv(ycbcr) }
-    fn from_yuv(yuv: Yuv<f32>) -> Self { yuv }
 }
 
 pub trait ConvertImage {
+    #[allow(dead_code)]
     fn to_rgb(&self) -> Image<Rgb<u8>>;
-    fn to_rgba(&self) -> Image<Rgba<u8>>;
-    fn to_ycbcr(&self) -> Image<YCbCr<u8>>;
+    fn to_ycbcr(&self, parameters: &YCbCrParameters) -> Image<YCbCr<u8>>;
     fn to_yuv(&self) -> Image<Yuv<f32>>;
 }
 
@@ -209,13 +281,13 @@ impl<P: PixelTrait + ConvertPixel> ConvertImage for Image<P> {
         new_image
     }
 
-    fn to_rgba(&self) -> Image<Rgba<u8>> {
-        let mut new_image = Image::<Rgba<u8>>::new(self.get_width(), self.get_height());
+    fn to_ycbcr(&self, parameters: &YCbCrParameters) -> Image<YCbCr<u8>> {
+        let mut new_image = Image::<YCbCr<u8>>::new(self.get_width(), self.get_height());
 
         for x in 0..self.get_width() {
             for y in 0..self.get_height() {
                 let pixel = self.get_pixel(x, y);
-                let new_pixel = pixel.to_rgba();
+                let new_pixel = pixel.to_ycbcr(parameters);
                 new_image.set_pixel(x, y, new_pixel.channels().as_slice());
             }
         }
@@ -223,31 +295,52 @@ impl<P: PixelTrait + ConvertPixel> ConvertImage for Image<P> {
         new_image
     }
 
-    fn to_ycbcr(&self) -> Image<YCbCr<u8>> {
-        let mut new_image = Image::<YCbCr<u8>>::new(self.get_width(), self.get_height());
+    fn to_yuv(&self) -> Image<Yuv<f32>> {
+        let mut new_image = Image::<Yuv<f32>>::new(self.get_width(), self.get_height());
 
         for x in 0..self.get_width() {
             for y in 0..self.get_height() {
                 let pixel = self.get_pixel(x, y);
-                let new_pixel = pixel.to_ycbcr();
+                let new_pixel = pixel.to_yuv();
                 new_image.set_pixel(x, y, new_pixel.channels().as_slice());
             }
         }
 
         new_image
     }
+}
 
-    fn to_yuv(&self) -> Image<Yuv<f32>> {
-        let mut new_image = Image::<Yuv<f32>>::new(self.get_width(), self.get_height());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+        Largest change of a channel over the RGB colors converted with the given conversions, taking
+        every third value of each channel (0 and 255 included, so the corners of the cube are tested).
+    */
+    fn max_round_trip_error(round_trip: impl Fn(Rgb<u8>) -> Rgb<u8>) -> u8 {
+        let values = || (0..=255_u8).step_by(3);
+        values().flat_map(|r| values().flat_map(move |g| values().map(move |b| (r, g, b)))).map(|(r, g, b)| {
+            let converted = round_trip(Rgb::new(r, g, b));
+            r.abs_diff(converted.get_red()).max(g.abs_diff(converted.get_green())).max(b.abs_diff(converted.get_blue()))
+        }).max().unwrap()
+    }
 
-        for x in 0..self.get_width() {
-            for y in 0..self.get_height() {
-                let pixel = self.get_pixel(x, y);
-                let new_pixel = pixel.to_yuv();
-                new_image.set_pixel(x, y, new_pixel.channels().as_slice());
+    #[test]
+    fn ycbcr_round_trip() {
+        for matrix in [YCbCrMatrix::Bt601, YCbCrMatrix::Bt709, YCbCrMatrix::Bt2020] {
+            for (range, tolerance) in [(YCbCrRange::Full, 1), (YCbCrRange::Studio, 2)] {
+                let parameters = YCbCrParameters::new(matrix, range);
+                assert!(max_round_trip_error(|rgb| ycbcr2rgb(rgb2ycbcr(rgb, &parameters), &parameters)) <= tolerance);
             }
         }
+    }
 
-        new_image
+    #[test]
+    fn yuv_round_trip() {
+        assert!(max_round_trip_error(|rgb| yuv2rgb(rgb2yuv(rgb))) <= 1);
+        assert_eq!(yuv2rgba(rgb2yuv(Rgb::new(255, 0, 0))), Rgba::new(255, 0, 0, 255));
+        // Negative chroma is kept until the end of the conversion
+        assert_eq!(yuv2rgb(Yuv::new(0., 0., -0.5)), Rgb::new(0, 74, 0));
     }
-}
\ No newline at end of file
+}
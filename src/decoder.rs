@@ -0,0 +1,565 @@
+use nalgebra::DMatrix;
+use crate::codestream::{
+    COC, COD, CodingStyle, EOC, ImageAndTileSize, POC, PPM, PPT, ProgressionOrderChange, QCC, QCD, QuantizationDefault, RGN,
+    SIZ, SOC, SOD, SOT,
+    read_u16, read_u32,
+};
+use crate::color_transform::{inverse_dc_level_shift, inverse_ict, inverse_rct};
+use crate::jp2::{is_jp2, read_jp2};
+use crate::progression::Progression;
+use crate::quantization::{QuantizationStyle, QuantizedSubband, Quantizer, expand_step_sizes};
+use crate::subband::Decomposition;
+use crate::tier1::CodedSubband;
+use crate::tier2::{PacketLayout, decode_packets};
+use crate::wavelet::{Cdf97, LeGall53, Wavelet};
+
+/*
+    Crate of the JPEG 2000 decoder: it reads the marker segments of a codestream (or of the codestream
+    of a JP2 file), then for each component reads the packets, decodes the code-blocks, dequantizes
+    the subbands and runs the inverse wavelet transform.
+
+    The decoder reads what the encoder writes: coding parameters and progression order changes in the
+    main header only, and no marker segment for a single component or a region of interest. Each tile
+    is decoded on its own (so a single tile can be decoded without the others), then placed in the
+    components. With the component transform, the first three components of a tile are transformed
+    back (RCT with the LeGall53 wavelet, ICT with the Cdf97 wavelet) before their DC level shift.
+*/
+
+/*
+    Codestream read from a file: its main header and the data of each tile (its tile-parts one after
+    the other, empty for a missing tile).
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Codestream {
+    size: ImageAndTileSize,
+    coding_style: CodingStyle,
+    quantization: QuantizationDefault,
+    progression_changes: Vec<Progression>,
+    tile_data: Vec<Vec<u8>>,
+}
+
+/*
+    Get the parameters of the marker segment starting at the given position
+    (None if the codestream is cut before the end of the segment).
+*/
+fn segment_parameters(data: &[u8], position: usize) -> Option<&[u8]> {
+    let length = read_u16(data.get(..position + 4)?, position + 2) as usize;
+    if length < 2 {
+        panic!("Invalid marker segment at byte {}.", position);
+    }
+    data.get(position + 4..position + 2 + length)
+}
+
+/*
+    Panic on the marker segments changing the decoding which are not supported.
+*/
+fn check_supported(marker: u16) {
+    if [COC, QCC, RGN, PPM, PPT].contains(&marker) {
+        panic!("The marker {:04X} is not supported.", marker);
+    }
+}
+
+impl Codestream {
+    /*
+        Read a raw codestream (.j2k). A truncated codestream is read as far as possible.
+    */
+    pub fn read(data: &[u8]) -> Self {
+        if read_u16(data, 0) != SOC {
+            panic!("The data is not a JPEG 2000 codestream.");
+        }
+
+        let (mut size, mut coding_style, mut quantization) = (None, None, None);
+        let mut progression_changes = Vec::new();
+        let mut tile_data: Vec<Vec<u8>> = Vec::new();
+        let mut position = 2;
+
+        while position + 2 <= data.len() {
+            let marker = read_u16(data, position);
+            match marker {
+                EOC => break,
+                SOT => {
+                    let parameters = match segment_parameters(data, position) {
+                        Some(parameters) if parameters.len() >= 8 => parameters,
+                        Some(_) => panic!("Invalid SOT marker segment at byte {}.", position),
+                        None => break,
+                    };
+                    let tile = read_u16(parameters, 0) as usize;
+                    if tile >= tile_data.len() {
+                        panic!("The codestream has no tile {}.", tile);
+                    }
+                    // A tile-part length of 0 means that the tile-part goes to EOC
+                    let end = match read_u32(parameters, 2) as usize {
+                        0 if data.ends_with(&EOC.to_be_bytes()) => data.len() - 2,
+                        0 => data.len(),
+                        length => (position + length).min(data.len()),
+                    };
+
+                    position += 4 + parameters.len();
+                    while position + 2 <= data.len() && read_u16(data, position) != SOD {
+                        let marker = read_u16(data, position);
+                        if [COD, QCD, POC].contains(&marker) {
+                            panic!("Tile-part headers with coding parameters are not supported.");
+                        }
+                        check_supported(marker);
+                        position = match segment_parameters(data, position) {
+                            Some(parameters) => position + 4 + parameters.len(),
+                            None => data.len(),
+                        };
+                    }
+                    // The codestream is cut in the tile-part header
+                    if position + 2 > data.len() {
+                        break;
+                    }
+
+                    tile_data[tile].extend_from_slice(&data[(position + 2).min(end)..end]);
+                    position = end;
+                },
+                _ => {
+                    let parameters = match segment_parameters(data, position) {
+                        Some(parameters) => parameters,
+                        None => break,
+                    };
+                    match marker {
+                        SIZ => {
+                            let image_size = ImageAndTileSize::read(parameters);
+                            tile_data = vec![Vec::new(); image_size.get_tile_count()];
+                            size = Some(image_size);
+                        },
+                        COD => coding_style = Some(CodingStyle::read(parameters)),
+                        QCD => quantization = Some(QuantizationDefault::read(parameters)),
+                        POC => {
+                            let component_count = size.as_ref().expect("POC comes before SIZ.").get_components().len();
+                            progression_changes.extend_from_slice(ProgressionOrderChange::read(parameters, component_count).get_progressions());
+                        },
+                        // Other marker segments (comments, lengths of the tile-parts and packets...) are skipped
+                        _ => check_supported(marker),
+                    }
+                    position += 4 + parameters.len();
+                },
+            }
+        }
+
+        Codestream {
+            size: size.expect("The codestream has no SIZ marker segment."),
+            coding_style: coding_style.expect("The codestream has no COD marker segment."),
+            quantization: quantization.expect("The codestream has no QCD marker segment."),
+            progression_changes,
+            tile_data,
+        }
+    }
+
+    pub fn get_size(&self) -> &ImageAndTileSize { &self.size }
+    #[allow(dead_code)]
+    pub fn get_coding_style(&self) -> &CodingStyle { &self.coding_style }
+    #[allow(dead_code)]
+    pub fn get_quantization(&self) -> &QuantizationDefault { &self.quantization }
+    #[allow(dead_code)]
+    pub fn get_progression_changes(&self) -> &Vec<Progression> { &self.progression_changes }
+
+    /*
+        Get the quantizer of a component with the given precision.
+    */
+    fn get_quantizer(&self, precision: u8) -> Quantizer {
+        let style = self.quantization.get_style();
+        let mut quantizer = match style {
+            QuantizationStyle::NoQuantization => Quantizer::reversible(precision),
+            _ => Quantizer::new(precision, 1.),
+        };
+        quantizer.set_style(style);
+        quantizer.set_guard_bits(self.quantization.get_guard_bits());
+        quantizer
+    }
+
+    /*
+        Decode the tile-components of a tile with the wavelet W from the first layers.
+    */
+    fn decode_tile_with<W: Wavelet>(&self, tile: usize, decoded_layers: usize) -> Vec<DMatrix<f32>> {
+        let level_count = self.coding_style.get_level_count() as usize;
+        let block_coder = self.coding_style.get_block_coder();
+        let block_coders = self.coding_style.get_subband_block_coders();
+        let step_sizes = expand_step_sizes(self.quantization.get_style(), self.quantization.get_step_sizes(), level_count);
+        let components = self.size.get_components();
+
+        // The subbands of the tile-components and their code-blocks, waiting for their coefficients and passes
+        let mut decompositions: Vec<Decomposition<W>> = (0..components.len()).map(|component| {
+            let ((x0, y0), (x1, y1)) = self.size.get_tile_component_bounds(tile, component);
+            Decomposition::zeros((x0, y0), x1 - x0, y1 - y0, level_count)
+        }).collect();
+        let mut coded: Vec<Vec<CodedSubband>> = decompositions.iter().zip(components).map(|(decomposition, component)| {
+            let quantizer = self.get_quantizer(component.get_precision());
+            decomposition.subbands().zip(&step_sizes).zip(&block_coders).map(|((subband, &step_size), block_coder)| {
+                block_coder.empty_subband(subband, step_size, quantizer.get_magnitude_bits(step_size))
+            }).collect()
+        }).collect();
+
+        // The progression order changes replace the progression order
+        let layer_count = self.coding_style.get_layer_count() as usize;
+        let progressions = match self.progression_changes.is_empty() {
+            true => vec![Progression::whole(self.coding_style.get_progression_order(), layer_count, level_count + 1, components.len())],
+            false => self.progression_changes.clone(),
+        };
+        let factors = components.iter().map(|component| (component.get_dx() as u32, component.get_dy() as u32)).collect();
+        let layout = PacketLayout::new(progressions, self.coding_style.get_precinct_sizes().clone(), self.size.get_tile_bounds(tile).0, factors);
+        decode_packets(&self.tile_data[tile], &mut coded, layer_count, decoded_layers, &layout, block_coder.get_style());
+
+        let mut samples: Vec<DMatrix<f32>> = decompositions.iter_mut().zip(&coded).zip(components).map(|((decomposition, subbands), component)| {
+            let quantizer = self.get_quantizer(component.get_precision());
+            let quantized: Vec<QuantizedSubband> = subbands.iter().map(|subband| block_coder.decode_subband(subband)).collect();
+            quantizer.dequantize(&quantized, decomposition);
+            decomposition.reconstruct()
+        }).collect();
+
+        if self.coding_style.get_component_transform() {
+            match W::REVERSIBLE {
+                true => inverse_rct(&mut samples),
+                false => inverse_ict(&mut samples),
+            }
+        }
+        for (sample, component) in samples.iter_mut().zip(components) {
+            if !component.get_signed() {
+                inverse_dc_level_shift(sample, component.get_precision());
+            }
+        }
+
+        samples
+    }
+
+    /*
+        Decode the components with the wavelet W from the first layers, tile by tile.
+    */
+    fn decode_with<W: Wavelet>(&self, decoded_layers: usize) -> Vec<DMatrix<f32>> {
+        let component_count = self.size.get_components().len();
+        let mut samples: Vec<DMatrix<f32>> = (0..component_count).map(|component| {
+            let ((x0, y0), (x1, y1)) = self.size.get_component_bounds(component);
+            DMatrix::zeros((y1 - y0) as usize, (x1 - x0) as usize)
+        }).collect();
+
+        for tile in 0..self.size.get_tile_count() {
+            for (component, tile_samples) in self.decode_tile_with::<W>(tile, decoded_layers).iter().enumerate() {
+                let ((x0, y0), _) = self.size.get_tile_component_bounds(tile, component);
+                let ((component_x0, component_y0), _) = self.size.get_component_bounds(component);
+                let start = ((y0 - component_y0) as usize, (x0 - component_x0) as usize);
+                samples[component].slice_mut(start, tile_samples.shape()).copy_from(tile_samples);
+            }
+        }
+
+        samples
+    }
+
+    /*
+        Decode the samples of each component (each row of a matrix is a line of the component).
+    */
+    pub fn decode(&self) -> Vec<DMatrix<f32>> {
+        self.decode_layers(self.coding_style.get_layer_count() as usize)
+    }
+
+    /*
+        Decode the samples of each component from the first quality layers only.
+    */
+    pub fn decode_layers(&self, layer_count: usize) -> Vec<DMatrix<f32>> {
+        match self.coding_style.get_transform_code() {
+            0 => self.decode_with::<Cdf97>(layer_count),
+            1 => self.decode_with::<LeGall53>(layer_count),
+            code => panic!("Unknown wavelet transform {}.", code),
+        }
+    }
+
+    /*
+        Decode the samples of each component in a single tile (numbered in raster order), without
+        decoding the other tiles.
+    */
+    #[allow(dead_code)]
+    pub fn decode_tile(&self, tile: usize) -> Vec<DMatrix<f32>> {
+        if tile >= self.size.get_tile_count() {
+            panic!("The codestream has no tile {}.", tile);
+        }
+        let layer_count = self.coding_style.get_layer_count() as usize;
+        match self.coding_style.get_transform_code() {
+            0 => self.decode_tile_with::<Cdf97>(tile, layer_count),
+            1 => self.decode_tile_with::<LeGall53>(tile, layer_count),
+            code => panic!("Unknown wavelet transform {}.", code),
+        }
+    }
+}
+
+/*
+    Read the codestream of a raw codestream (.j2k) or of a JP2 file.
+*/
+pub fn read_codestream(data: &[u8]) -> Codestream {
+    if is_jp2(data) {
+        Codestream::read(read_jp2(data))
+    } else {
+        Codestream::read(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codestream::Encoder;
+    use crate::color::Rgba;
+    use crate::color_transform::ComponentTransform;
+    use crate::jp2::Resolution;
+    use crate::my_image::Image;
+    use crate::progression::ProgressionOrder;
+    use crate::rate_control::LayerTarget;
+    use crate::subsampling::{ChromaSubsampling, DownsamplingFilter};
+    use crate::tier1::{BlockCoder, CodeBlockStyle};
+
+    /*
+        Image with a gradient and pseudo-random noise (from a linear congruential generator) on
+        each channel, alpha included.
+    */
+    fn image(width: usize, height: usize, seed: u32) -> Image<Rgba<u8>> {
+        let mut state = seed;
+        let mut noise = || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as usize % 32
+        };
+        Image::from_rgba_matrix(&DMatrix::from_fn(height, width, |y, x| {
+            Rgba::new((x * 5 + noise()) as u8, (y * 7 + noise()) as u8, ((x + y) * 3 + noise()) as u8, (255 - noise()) as u8)
+        }))
+    }
+
+    /*
+        Samples of the components of an image, as decoded (without the DC level shift).
+    */
+    fn samples(image: &Image<Rgba<u8>>) -> Vec<DMatrix<f32>> {
+        image.to_components().iter().map(|component| component.add_scalar(128.)).collect()
+    }
+
+    fn tiled_encoder() -> Encoder<LeGall53> {
+        let mut encoder = Encoder::<LeGall53>::new();
+        encoder.set_tile_size(Some((16, 16)));
+        encoder.set_image_offset((5, 3));
+        encoder.set_tile_origin((2, 1));
+        encoder.set_level_count(3);
+        encoder
+    }
+
+    #[test]
+    fn lossless_round_trip() {
+        let image = image(37, 29, 1);
+        let mut without_transform = Encoder::<LeGall53>::new();
+        without_transform.set_component_transform(false);
+
+        for encoder in [Encoder::<LeGall53>::new(), without_transform, tiled_encoder()] {
+            let codestream = Codestream::read(&encoder.encode(&image));
+            assert_eq!(codestream.decode(), samples(&image));
+        }
+    }
+
+    /*
+        Set two quality layers in an encoder, the last one with all the coding passes.
+    */
+    fn set_two_layers(encoder: &mut Encoder<LeGall53>) {
+        encoder.set_layer_targets(vec![LayerTarget::BitsPerPixel(2.), LayerTarget::BitsPerPixel(1000.)]);
+    }
+
+    #[test]
+    fn progression_order_round_trips() {
+        let image = image(37, 29, 5);
+        let orders = [ProgressionOrder::Lrcp, ProgressionOrder::Rlcp, ProgressionOrder::Rpcl, ProgressionOrder::Pcrl, ProgressionOrder::Cprl];
+
+        for order in orders {
+            for mut encoder in [Encoder::<LeGall53>::new(), tiled_encoder()] {
+                encoder.set_progression_order(order);
+                set_two_layers(&mut encoder);
+                let codestream = Codestream::read(&encoder.encode(&image));
+                assert_eq!(codestream.get_coding_style().get_progression_order(), order);
+                assert_eq!(codestream.decode(), samples(&image), "{:?}", order);
+                assert_ne!(codestream.decode_layers(1), samples(&image), "{:?}", order);
+            }
+        }
+    }
+
+    #[test]
+    fn progression_order_change_round_trip() {
+        let image = image(37, 29, 6);
+        // The first layer of the two lowest resolutions of the colour components, then the rest
+        let progressions = vec![
+            Progression::new(ProgressionOrder::Rpcl, 1, 0, 2, 0, 3),
+            Progression::whole(ProgressionOrder::Cprl, 2, 4, 4),
+        ];
+
+        for mut encoder in [Encoder::<LeGall53>::new(), tiled_encoder()] {
+            encoder.set_level_count(3);
+            set_two_layers(&mut encoder);
+            encoder.set_progression_changes(progressions.clone());
+            let codestream = Codestream::read(&encoder.encode(&image));
+            // The progression order follows the changes, for the remaining packets
+            assert!(codestream.get_progression_changes().starts_with(&progressions));
+            assert_eq!(codestream.decode(), samples(&image));
+        }
+    }
+
+    #[test]
+    fn precinct_round_trips() {
+        // Several precincts in each resolution level, with and without tiles
+        let mut untiled = Encoder::<LeGall53>::new();
+        untiled.set_level_count(3);
+        let cases = [
+            (untiled, image(150, 109, 7), vec![(4, 4), (5, 4), (5, 5), (6, 6)]),
+            (tiled_encoder(), image(37, 29, 8), vec![(2, 2), (3, 2), (3, 3), (4, 4)]),
+        ];
+
+        for (mut encoder, image, sizes) in cases {
+            for order in [ProgressionOrder::Rpcl, ProgressionOrder::Pcrl] {
+                encoder.set_progression_order(order);
+                encoder.set_precinct_sizes(sizes.clone());
+                let data = encoder.encode(&image);
+
+                // Scod has the precinct bit, and PPx and PPy follow the other parameters of COD
+                let cod = data.windows(2).position(|marker| read_u16(marker, 0) == COD).unwrap();
+                assert_eq!(data[cod + 4] & 1, 1);
+                let precincts: Vec<u8> = sizes.iter().map(|(width, height)| width | height << 4).collect();
+                assert_eq!(data[cod + 14..cod + 18], precincts);
+
+                let codestream = Codestream::read(&data);
+                assert_eq!(codestream.get_coding_style().get_precinct_sizes(), &sizes);
+                assert_eq!(codestream.decode(), samples(&image), "{:?}", order);
+            }
+        }
+    }
+
+    #[test]
+    fn code_block_style_round_trips() {
+        let image = image(37, 29, 11);
+        let mut all = CodeBlockStyle::new();
+        all.set_reset(true);
+        all.set_terminate_all(true);
+        all.set_vertically_causal(true);
+        all.set_predictable_termination(true);
+        all.set_segmentation_symbols(true);
+        let mut causal = CodeBlockStyle::new();
+        causal.set_reset(true);
+        causal.set_vertically_causal(true);
+
+        for style in [all, causal] {
+            for mut encoder in [Encoder::<LeGall53>::new(), tiled_encoder()] {
+                let mut block_coder = BlockCoder::new();
+                block_coder.set_block_size(16, 8);
+                block_coder.set_style(style);
+                encoder.set_block_coder(block_coder);
+                set_two_layers(&mut encoder);
+                let data = encoder.encode(&image);
+
+                // The exponents of the code-block size minus 2 and the style follow the levels in COD
+                let cod = data.windows(2).position(|marker| read_u16(marker, 0) == COD).unwrap();
+                assert_eq!(data[cod + 10..cod + 13], [2, 1, style.get_code()]);
+                assert_eq!(Codestream::read(&data).decode(), samples(&image), "{:?}", style);
+            }
+        }
+    }
+
+    #[test]
+    fn jp2_round_trip() {
+        let image = image(33, 18, 2);
+        let mut encoder = tiled_encoder();
+        encoder.set_resolution(Some(Resolution::from_dpi(96., 96.)));
+        let data = encoder.encode_jp2(&image);
+        assert!(is_jp2(&data));
+        // The resolution is written as the display resolution
+        assert!(data.windows(4).any(|box_type| box_type == b"resd"));
+        assert!(!data.windows(4).any(|box_type| box_type == b"resc"));
+        assert_eq!(read_codestream(&data).decode(), samples(&image));
+
+        let decoded = Image::<Rgba<u8>>::from_jpeg2000(&data);
+        for (y, x) in (0..18).flat_map(|y| (0..33).map(move |x| (y, x))) {
+            assert_eq!(decoded.get_pixel(x, y), image.get_pixel(x, y));
+        }
+    }
+
+    #[test]
+    fn subsampled_jp2_round_trip() {
+        let image = image(37, 29, 9);
+        let mut tiled = tiled_encoder();
+        // The offsets and the tiles line up with the subsampled components
+        tiled.set_image_offset((4, 2));
+        tiled.set_tile_origin((2, 0));
+
+        for (mut encoder, filter) in [(Encoder::<LeGall53>::new(), DownsamplingFilter::Box), (tiled, DownsamplingFilter::LowPass)] {
+            encoder.set_subsampling(ChromaSubsampling::Yuv420);
+            encoder.set_downsampling_filter(filter);
+            let data = encoder.encode_jp2(&image);
+
+            // Ssiz, XRsiz and YRsiz of each component, after SOC, the SIZ marker, Lsiz and the 36 bytes of the size
+            let codestream = read_jp2(&data);
+            assert_eq!(read_u16(codestream, 2), SIZ);
+            let factors: Vec<[u8; 2]> = codestream[42..54].chunks(3).map(|component| [component[1], component[2]]).collect();
+            assert_eq!(factors, [[1, 1], [2, 2], [2, 2], [1, 1]]);
+
+            // The chroma loses its details, but not the alpha channel
+            let decoded = Image::<Rgba<u8>>::from_jpeg2000(&data);
+            let errors: Vec<u8> = (0..29).flat_map(|y| (0..37).map(move |x| (x, y))).flat_map(|(x, y)| {
+                let (pixel, decoded) = (image.get_pixel(x, y), decoded.get_pixel(x, y));
+                assert_eq!(decoded.get_alpha(), pixel.get_alpha());
+                [
+                    pixel.get_red().abs_diff(decoded.get_red()),
+                    pixel.get_green().abs_diff(decoded.get_green()),
+                    pixel.get_blue().abs_diff(decoded.get_blue()),
+                ]
+            }).collect();
+            assert!(errors.iter().all(|&error| error <= 32));
+            assert!(errors.iter().map(|&error| error as f32).sum::<f32>() / (errors.len() as f32) < 8.);
+        }
+    }
+
+    #[test]
+    fn derived_quantization_round_trip() {
+        let image = image(37, 29, 10);
+        let expected = samples(&image);
+
+        for style in [QuantizationStyle::ScalarExpounded, QuantizationStyle::ScalarDerived] {
+            let mut quantizer = Quantizer::new(8, 1.);
+            quantizer.set_style(style);
+            let mut encoder = Encoder::<Cdf97>::new();
+            encoder.set_quantizer(quantizer);
+            let codestream = Codestream::read(&encoder.encode(&image));
+
+            // A single step size is signalled in the derived style
+            assert_eq!(codestream.get_quantization().get_style(), style);
+            assert_eq!(codestream.get_quantization().get_step_sizes().len(), if style == QuantizationStyle::ScalarDerived { 1 } else { 16 });
+
+            let error = codestream.decode().iter().zip(&expected).map(|(decoded, expected)| (decoded - expected).amax()).fold(0., f32::max);
+            assert!(error < 4., "{:?}: error of {}", style, error);
+        }
+    }
+
+    #[test]
+    fn decode_single_tiles() {
+        let codestream = Codestream::read(&tiled_encoder().encode(&image(40, 27, 3)));
+        let size = codestream.get_size();
+        assert!(size.get_tile_count() > 1);
+
+        let samples = codestream.decode();
+        for tile in 0..size.get_tile_count() {
+            for (component, tile_samples) in codestream.decode_tile(tile).iter().enumerate() {
+                let ((x0, y0), _) = size.get_tile_component_bounds(tile, component);
+                let ((component_x0, component_y0), _) = size.get_component_bounds(component);
+                let start = ((y0 - component_y0) as usize, (x0 - component_x0) as usize);
+                assert_eq!(samples[component].slice(start, tile_samples.shape()), *tile_samples);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_tile_part_headers() {
+        let data = tiled_encoder().encode(&image(24, 20, 4));
+        let tile_parts: Vec<usize> = data.windows(2).enumerate()
+            .filter(|(_, marker)| read_u16(marker, 0) == SOT)
+            .map(|(position, _)| position)
+            .collect();
+        assert!(tile_parts.len() > 1);
+
+        // Cut anywhere from the SOT marker to the end of the SOD marker
+        for &position in &tile_parts {
+            assert_eq!(read_u16(&data, position + 12), SOD);
+            for end in position..=position + 14 {
+                let samples = Codestream::read(&data[..end]).decode();
+                assert_eq!(samples.len(), 4);
+                assert!(samples.iter().all(|component| component.shape() == (20, 24)));
+            }
+        }
+    }
+}
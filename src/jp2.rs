@@ -0,0 +1,316 @@
+use crate::codestream::{Component, read_u32, write_u16, write_u32};
+
+/*
+    Crate of the JP2 file format (Annex I of the spec): the codestream wrapped in boxes describing
+    the image for the applications.
+
+    Each box is made of its length (which counts the whole box), its type (4 characters) and its
+    content. A JP2 file is made of:
+    - the signature box ('jP  '),
+    - the file type box ('ftyp'),
+    - the header superbox ('jp2h'), with the image header ('ihdr'), the colour specification ('colr'),
+      the channel definition ('cdef') when there is an alpha channel, and the resolution ('res ') if known,
+    - the contiguous codestream box ('jp2c').
+*/
+
+/*
+    Write a box with its content.
+*/
+pub fn write_box(bytes: &mut Vec<u8>, box_type: &[u8; 4], content: &[u8]) {
+    write_u32(bytes, content.len() as u32 + 8);
+    bytes.extend_from_slice(box_type);
+    bytes.extend_from_slice(content);
+}
+
+/*
+    Enumerated colour spaces of the colour specification box.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColourSpace {
+    Srgb,
+    Greyscale,
+    Sycc,
+}
+
+impl ColourSpace {
+    pub fn get_code(&self) -> u32 {
+        match self {
+            ColourSpace::Srgb => 16,
+            ColourSpace::Greyscale => 17,
+            ColourSpace::Sycc => 18,
+        }
+    }
+
+    /*
+        Get the colour space of an enumerated code (None for the other colour spaces, such as CMYK).
+    */
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            16 => Some(ColourSpace::Srgb),
+            17 => Some(ColourSpace::Greyscale),
+            18 => Some(ColourSpace::Sycc),
+            _ => None,
+        }
+    }
+
+    /*
+        Get the number of colour channels of the colour space.
+    */
+    pub fn get_channel_count(&self) -> usize {
+        match self {
+            ColourSpace::Greyscale => 1,
+            _ => 3,
+        }
+    }
+}
+
+/*
+    Resolution of the image, in pixels per metre horizontally and vertically.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resolution {
+    horizontal: f64,
+    vertical: f64,
+}
+
+impl Resolution {
+    #[allow(dead_code)]
+    pub fn new(horizontal: f64, vertical: f64) -> Self {
+        if horizontal <= 0. || vertical <= 0. {
+            panic!("The resolution must be positive.");
+        }
+        Resolution {horizontal, vertical}
+    }
+
+    /*
+        Create a resolution from dots per inch.
+    */
+    #[allow(dead_code)]
+    pub fn from_dpi(horizontal: f64, vertical: f64) -> Self {
+        Resolution::new(horizontal / 0.0254, vertical / 0.0254)
+    }
+
+    /*
+        Split a resolution into the numerator, denominator and exponent of the resolution boxes
+        (value = numerator / denominator * 10^exponent), keeping 4 or 5 significant digits.
+    */
+    fn split(value: f64) -> (u16, u16, i8) {
+        let exponent = (value.log10().floor() as i32 - 4).clamp(-128, 127);
+        let numerator = (value / 10_f64.powi(exponent)).round();
+        if numerator > u16::MAX as f64 {
+            (((numerator / 10.).round()) as u16, 1, (exponent + 1) as i8)
+        } else {
+            (numerator as u16, 1, exponent as i8)
+        }
+    }
+
+    /*
+        Write the content of a capture ('resc') or display ('resd') resolution box.
+    */
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let (vertical_numerator, vertical_denominator, vertical_exponent) = Resolution::split(self.vertical);
+        let (horizontal_numerator, horizontal_denominator, horizontal_exponent) = Resolution::split(self.horizontal);
+
+        write_u16(bytes, vertical_numerator);
+        write_u16(bytes, vertical_denominator);
+        write_u16(bytes, horizontal_numerator);
+        write_u16(bytes, horizontal_denominator);
+        bytes.push(vertical_exponent as u8);
+        bytes.push(horizontal_exponent as u8);
+    }
+}
+
+/*
+    Content of the JP2 header superbox: the size and components of the image, its colour space
+    and its capture and display resolutions if they are known.
+    The components after the colour channels are alpha channels.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Jp2Header {
+    width: u32,
+    height: u32,
+    components: Vec<Component>,
+    colour_space: ColourSpace,
+    capture_resolution: Option<Resolution>,
+    display_resolution: Option<Resolution>,
+}
+
+impl Jp2Header {
+    pub fn new(width: u32, height: u32, components: Vec<Component>, colour_space: ColourSpace) -> Self {
+        if components.len() < colour_space.get_channel_count() {
+            panic!("{} components are not enough for the colour space {:?}.", components.len(), colour_space);
+        }
+        Jp2Header {width, height, components, colour_space, capture_resolution: None, display_resolution: None}
+    }
+
+    #[allow(dead_code)]
+    pub fn set_capture_resolution(&mut self, resolution: Option<Resolution>) { self.capture_resolution = resolution; }
+    pub fn set_display_resolution(&mut self, resolution: Option<Resolution>) { self.display_resolution = resolution; }
+
+    /*
+        Write the header superbox.
+    */
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        let mut content = Vec::new();
+
+        // Image header: size, number of components, bits per component (255 if they differ),
+        // compression type (7), known colour space (UnkC = 0) and no intellectual property (0)
+        let mut header = Vec::new();
+        write_u32(&mut header, self.height);
+        write_u32(&mut header, self.width);
+        write_u16(&mut header, self.components.len() as u16);
+        let depth = |component: &Component| (component.get_precision() - 1) | (component.get_signed() as u8) << 7;
+        let same_depth = self.components.windows(2).all(|pair| depth(&pair[0]) == depth(&pair[1]));
+        header.push(if same_depth { depth(&self.components[0]) } else { 255 });
+        header.extend_from_slice(&[7, 0, 0]);
+        write_box(&mut content, b"ihdr", &header);
+
+        // Colour specification: enumerated method (1), precedence and approximation (0)
+        let mut colour = vec![1, 0, 0];
+        write_u32(&mut colour, self.colour_space.get_code());
+        write_box(&mut content, b"colr", &colour);
+
+        // Channel definition: the colour channels in order, then the alpha channels for the whole image
+        let colour_count = self.colour_space.get_channel_count();
+        if self.components.len() > colour_count {
+            let mut definition = Vec::new();
+            write_u16(&mut definition, self.components.len() as u16);
+            for channel in 0..self.components.len() {
+                let (channel_type, association) = if channel < colour_count { (0, channel + 1) } else { (1, 0) };
+                write_u16(&mut definition, channel as u16);
+                write_u16(&mut definition, channel_type);
+                write_u16(&mut definition, association as u16);
+            }
+            write_box(&mut content, b"cdef", &definition);
+        }
+
+        if self.capture_resolution.is_some() || self.display_resolution.is_some() {
+            let mut resolution = Vec::new();
+            for (box_type, value) in [(b"resc", self.capture_resolution), (b"resd", self.display_resolution)] {
+                if let Some(value) = value {
+                    let mut box_content = Vec::new();
+                    value.write(&mut box_content);
+                    write_box(&mut resolution, box_type, &box_content);
+                }
+            }
+            write_box(&mut content, b"res ", &resolution);
+        }
+
+        write_box(bytes, b"jp2h", &content);
+    }
+}
+
+/*
+    Wrap a codestream in a JP2 file.
+*/
+pub fn write_jp2(header: &Jp2Header, codestream: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    write_box(&mut bytes, b"jP  ", &[0x0D, 0x0A, 0x87, 0x0A]);
+
+    // File type: brand, minor version and compatibility list
+    let mut file_type = b"jp2 ".to_vec();
+    write_u32(&mut file_type, 0);
+    file_type.extend_from_slice(b"jp2 ");
+    write_box(&mut bytes, b"ftyp", &file_type);
+
+    header.write(&mut bytes);
+    write_box(&mut bytes, b"jp2c", codestream);
+
+    bytes
+}
+
+/*
+    Whether the data starts with the JP2 signature box.
+*/
+pub fn is_jp2(data: &[u8]) -> bool {
+    data.starts_with(&[0, 0, 0, 12, b'j', b'P', b' ', b' ', 0x0D, 0x0A, 0x87, 0x0A])
+}
+
+/*
+    Find the content of the first box of the given type in a sequence of boxes.
+    A box length of 0 means that the box goes to the end of the data, and a length of 1 that the
+    length is given on 8 bytes after the type.
+*/
+fn find_box<'a>(data: &'a [u8], wanted: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut position = 0;
+
+    while position + 8 <= data.len() {
+        let box_type = &data[position + 4..position + 8];
+        let (header, length) = match read_u32(data, position) {
+            0 => (8, data.len() - position),
+            1 => (16, ((read_u32(data, position + 8) as usize) << 32) | read_u32(data, position + 12) as usize),
+            length => (8, length as usize),
+        };
+        if length < header {
+            panic!("Invalid length of the box {:?}.", String::from_utf8_lossy(box_type));
+        }
+
+        let end = (position + length).min(data.len());
+        if box_type == wanted {
+            return Some(&data[position + header..end]);
+        }
+        position = end;
+    }
+
+    None
+}
+
+/*
+    Get the codestream of a JP2 file: the content of its first contiguous codestream box.
+*/
+pub fn read_jp2(data: &[u8]) -> &[u8] {
+    find_box(data, b"jp2c").unwrap_or_else(|| panic!("The JP2 file has no codestream."))
+}
+
+/*
+    Get the enumerated colour space of a JP2 file (None if it has none, or one not supported).
+*/
+pub fn read_colour_space(data: &[u8]) -> Option<ColourSpace> {
+    let colour = find_box(find_box(data, b"jp2h")?, b"colr")?;
+    match colour.first() {
+        Some(1) if colour.len() >= 7 => ColourSpace::from_code(read_u32(colour, 3)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_colour_space() {
+        let header = Jp2Header::new(8, 8, vec![Component::new(8, false, 1, 1); 4], ColourSpace::Srgb);
+        let mut data = write_jp2(&header, &[]);
+        assert_eq!(read_colour_space(&data), Some(ColourSpace::Srgb));
+
+        // CMYK (12) is a valid enumerated colour space, read as unspecified
+        let position = data.windows(4).position(|box_type| box_type == b"colr").unwrap() + 7;
+        data[position..position + 4].copy_from_slice(&12_u32.to_be_bytes());
+        assert_eq!(read_colour_space(&data), None);
+    }
+
+    /*
+        Numerators and denominators (vertical, then horizontal) and exponents of a resolution box.
+    */
+    fn read_resolution(content: &[u8]) -> ([u16; 4], [i8; 2]) {
+        let values = [0, 2, 4, 6].map(|position| u16::from_be_bytes([content[position], content[position + 1]]));
+        (values, [content[8] as i8, content[9] as i8])
+    }
+
+    #[test]
+    fn resolution_boxes() {
+        let mut header = Jp2Header::new(8, 8, vec![Component::new(8, false, 1, 1); 3], ColourSpace::Srgb);
+        let data = write_jp2(&header, &[]);
+        assert!(find_box(find_box(&data, b"jp2h").unwrap(), b"res ").is_none());
+
+        header.set_capture_resolution(Some(Resolution::from_dpi(300., 300.)));
+        header.set_display_resolution(Some(Resolution::new(72.5, 2835.)));
+        let data = write_jp2(&header, &[]);
+        let resolution = find_box(find_box(&data, b"jp2h").unwrap(), b"res ").unwrap();
+
+        // 300 dpi are 11811 pixels per metre, and 72500 * 10^-3 does not fit on 16 bits
+        assert_eq!(read_resolution(find_box(resolution, b"resc").unwrap()), ([11811, 1, 11811, 1], [0, 0]));
+        assert_eq!(read_resolution(find_box(resolution, b"resd").unwrap()), ([28350, 1, 7250, 1], [-1, -2]));
+    }
+}
@@ -3,18 +3,32 @@ mod color;
 mod my_image;
 mod pixel_type;
 mod conversion;
+mod color_transform;
+mod subsampling;
+mod wavelet;
+mod subband;
+mod quantization;
+mod mq;
+mod tier1;
+mod tag_tree;
+mod tier2;
+mod progression;
+mod rate_control;
+mod codestream;
+mod jp2;
+mod decoder;
 
 use image::DynamicImage;
 use std::path::Path;
-use crate::conversion::ConvertImage;
+use crate::conversion::{ConvertImage, YCbCrParameters};
 use crate::my_image::Image;
-use crate::color::{YCbCr, Rgb, Yuv};
-//use my_image::split;
-
-//use crate::compress::Compress;
+use crate::color::{Rgb, Yuv};
+use crate::codestream::Encoder;
+use crate::wavelet::{Cdf97, LeGall53};
 
 const IMG_PATH: &str = "input/input.png";
 const SAVE_PATH: &str = "output/";
+const LEVELS: usize = 3;
 
 fn main() {
     let image: DynamicImage = image::open(Path::new(IMG_PATH)).unwrap();
@@ -32,12 +46,35 @@ fn main() {
     u_image.save(&(SAVE_PATH.to_owned() + "U.png"));
     v_image.save(&(SAVE_PATH.to_owned() + "V.png"));
 
-    let ycbcr_image = imp_image.to_ycbcr();
+    let ycbcr_image = imp_image.to_ycbcr(&YCbCrParameters::default());
     let (luma_image, cb_image, cr_image) = Image::split(&ycbcr_image);
 
     luma_image.save(&(SAVE_PATH.to_owned() + "Luma.png"));
     cb_image.save(&(SAVE_PATH.to_owned() + "Cb.png"));
     cr_image.save(&(SAVE_PATH.to_owned() + "Cr.png"));
 
+    let decompositions = yuv_image.decompose::<Cdf97>(LEVELS);
+    let subbands: Vec<_> = decompositions.iter().map(|decomposition| decomposition.to_matrix()).collect();
+    let subbands_image = Image::<Yuv<f32>>::from_channel_matrices(&subbands);
+    let (y_subbands, u_subbands, v_subbands) = Image::split(&subbands_image);
+
+    y_subbands.save(&(SAVE_PATH.to_owned() + "Y_subbands.png"));
+    u_subbands.save(&(SAVE_PATH.to_owned() + "U_subbands.png"));
+    v_subbands.save(&(SAVE_PATH.to_owned() + "V_subbands.png"));
+
+    let reconstructed_image = Image::from_decompositions(&decompositions);
+    reconstructed_image.save(&(SAVE_PATH.to_owned() + "Reconstructed.png"));
+
+    imp_image.save_j2k(&(SAVE_PATH.to_owned() + "Lossless.j2k"), &Encoder::<LeGall53>::new());
+    imp_image.save_jp2(&(SAVE_PATH.to_owned() + "Lossy.jp2"), &Encoder::<Cdf97>::new());
+
+    let lossless_data = std::fs::read(SAVE_PATH.to_owned() + "Lossless.j2k").unwrap();
+    let lossless_image = Image::<Rgb<u8>>::from_jpeg2000(&lossless_data);
+    lossless_image.save(&(SAVE_PATH.to_owned() + "Lossless.png"));
+
+    let lossy_data = std::fs::read(SAVE_PATH.to_owned() + "Lossy.jp2").unwrap();
+    let lossy_image = Image::<Rgb<u8>>::from_jpeg2000(&lossy_data);
+    lossy_image.save(&(SAVE_PATH.to_owned() + "Lossy.png"));
+
     println!("Images saved!");
-}
\ No newline at end of file
+}
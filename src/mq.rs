@@ -0,0 +1,472 @@
+/*
+    Crate of the MQ coder, the binary adaptive arithmetic coder of JPEG 2000 (Annex C of the spec).
+
+    Each decision is coded in a context, which keeps an estimate of the probability of the less
+    probable symbol (LPS) as an index in the state table, and the value of the more probable
+    symbol (MPS). The code register C and the interval register A are renormalized bit by bit,
+    and the bytes are written with a stuffed 0 bit after each 0xFF, so that the codestream never
+    contains a marker (0xFF followed by a byte above 0x8F).
+*/
+
+/*
+    Number of contexts used by the EBCOT coder:
+    - 0 to 8: significance (zero coding),
+    - 9 to 13: sign,
+    - 14 to 16: magnitude refinement,
+    - 17: run-length,
+    - 18: uniform.
+*/
+pub const CONTEXT_COUNT: usize = 19;
+pub const RUN_LENGTH_CONTEXT: usize = 17;
+pub const UNIFORM_CONTEXT: usize = 18;
+
+/*
+    Probability estimation state of the MQ coder:
+    - the probability of the LPS (Qe),
+    - the next state after coding a MPS (NMPS) or a LPS (NLPS),
+    - whether the MPS value switches after coding a LPS.
+*/
+struct State {
+    qe: u32,
+    nmps: usize,
+    nlps: usize,
+    switch: bool,
+}
+
+const fn state(qe: u32, nmps: usize, nlps: usize, switch: bool) -> State {
+    State {qe, nmps, nlps, switch}
+}
+
+const STATES: [State; 47] = [
+    state(0x5601, 1, 1, true), state(0x3401, 2, 6, false), state(0x1801, 3, 9, false),
+    state(0x0AC1, 4, 12, false), state(0x0521, 5, 29, false), state(0x0221, 38, 33, false),
+    state(0x5601, 7, 6, true), state(0x5401, 8, 14, false), state(0x4801, 9, 14, false),
+    state(0x3801, 10, 14, false), state(0x3001, 11, 17, false), state(0x2401, 12, 18, false),
+    state(0x1C01, 13, 20, false), state(0x1601, 29, 21, false), state(0x5601, 15, 14, true),
+    state(0x5401, 16, 14, false), state(0x5101, 17, 15, false), state(0x4801, 18, 16, false),
+    state(0x3801, 19, 17, false), state(0x3401, 20, 18, false), state(0x3001, 21, 19, false),
+    state(0x2801, 22, 19, false), state(0x2401, 23, 20, false), state(0x2201, 24, 21, false),
+    state(0x1C01, 25, 22, false), state(0x1801, 26, 23, false), state(0x1601, 27, 24, false),
+    state(0x1401, 28, 25, false), state(0x1201, 29, 26, false), state(0x1101, 30, 27, false),
+    state(0x0AC1, 31, 28, false), state(0x09C1, 32, 29, false), state(0x08A1, 33, 30, false),
+    state(0x0521, 34, 31, false), state(0x0441, 35, 32, false), state(0x02A1, 36, 33, false),
+    state(0x0221, 37, 34, false), state(0x0141, 38, 35, false), state(0x0111, 39, 36, false),
+    state(0x0085, 40, 37, false), state(0x0049, 41, 38, false), state(0x0025, 42, 39, false),
+    state(0x0015, 43, 40, false), state(0x0009, 44, 41, false), state(0x0005, 45, 42, false),
+    state(0x0001, 45, 43, false), state(0x5601, 46, 46, false),
+];
+
+/*
+    State index and MPS value of a context.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Context {
+    index: usize,
+    mps: u8,
+}
+
+/*
+    Initial contexts: the uniform context starts in state 46, the run-length context in state 3,
+    the first significance context in state 4 and all the others in state 0, with a MPS of 0.
+*/
+fn initial_contexts() -> [Context; CONTEXT_COUNT] {
+    let mut contexts = [Context {index: 0, mps: 0}; CONTEXT_COUNT];
+    contexts[0].index = 4;
+    contexts[RUN_LENGTH_CONTEXT].index = 3;
+    contexts[UNIFORM_CONTEXT].index = 46;
+
+    contexts
+}
+
+/*
+    MQ encoder. The bytes are written in a buffer whose first byte is a placeholder for the byte
+    "before the start" of the codeword, which is never output.
+*/
+pub struct MqEncoder {
+    a: u32,
+    c: u32,
+    ct: u32,
+    buffer: Vec<u8>,
+    contexts: [Context; CONTEXT_COUNT],
+}
+
+impl Default for MqEncoder {
+    fn default() -> Self { Self::new() }
+}
+
+impl MqEncoder {
+    pub fn new() -> Self {
+        MqEncoder {a: 0x8000, c: 0, ct: 12, buffer: vec![0], contexts: initial_contexts()}
+    }
+
+    /*
+        Set every context back to its initial state.
+    */
+    pub fn reset_contexts(&mut self) {
+        self.contexts = initial_contexts();
+    }
+
+    /*
+        Start a new codeword after a termination (the contexts are kept).
+    */
+    pub fn restart(&mut self) {
+        self.a = 0x8000;
+        self.c = 0;
+        self.ct = if self.last_byte() == 0xFF { 13 } else { 12 };
+    }
+
+    fn last_byte(&self) -> u8 { *self.buffer.last().unwrap() }
+
+    /*
+        Encode a decision (0 or 1) in the given context.
+    */
+    pub fn encode(&mut self, bit: u8, context: usize) {
+        let Context {index, mps} = self.contexts[context];
+        let state = &STATES[index];
+        self.a -= state.qe;
+
+        if bit == mps {
+            // MPS: the interval is reduced, and renormalized when it becomes too small
+            if self.a & 0x8000 == 0 {
+                if self.a < state.qe { self.a = state.qe; } else { self.c += state.qe; }
+                self.contexts[context].index = state.nmps;
+                self.renormalize();
+            } else {
+                self.c += state.qe;
+            }
+        } else {
+            // LPS: the interval becomes the LPS sub-interval (or the MPS one when it is smaller)
+            if self.a < state.qe { self.c += state.qe; } else { self.a = state.qe; }
+            if state.switch {
+                self.contexts[context].mps = 1 - mps;
+            }
+            self.contexts[context].index = state.nlps;
+            self.renormalize();
+        }
+    }
+
+    fn renormalize(&mut self) {
+        loop {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                self.byte_out();
+            }
+            if self.a & 0x8000 != 0 {
+                break;
+            }
+        }
+    }
+
+    /*
+        Output a byte of the code register, propagating the carry to the previous byte, and
+        leaving a 0 bit after each 0xFF.
+    */
+    fn byte_out(&mut self) {
+        if self.last_byte() == 0xFF {
+            self.buffer.push((self.c >> 20) as u8);
+            self.c &= 0xFFFFF;
+            self.ct = 7;
+        } else if self.c < 0x8000000 {
+            self.buffer.push((self.c >> 19) as u8);
+            self.c &= 0x7FFFF;
+            self.ct = 8;
+        } else {
+            *self.buffer.last_mut().unwrap() += 1;
+            if self.last_byte() == 0xFF {
+                self.c &= 0x7FFFFFF;
+                self.buffer.push((self.c >> 20) as u8);
+                self.c &= 0xFFFFF;
+                self.ct = 7;
+            } else {
+                self.buffer.push((self.c >> 19) as u8);
+                self.c &= 0x7FFFF;
+                self.ct = 8;
+            }
+        }
+    }
+
+    /*
+        Terminate the codeword with the standard flush: the code register is set to the value of
+        the interval with the most trailing 1 bits, and its remaining bytes are output.
+        A last 0xFF is not needed by the decoder and is removed.
+    */
+    pub fn flush(&mut self) {
+        let temp = self.c + self.a;
+        self.c |= 0xFFFF;
+        if self.c >= temp {
+            self.c -= 0x8000;
+        }
+
+        self.c <<= self.ct;
+        self.byte_out();
+        self.c <<= self.ct;
+        self.byte_out();
+
+        if self.last_byte() == 0xFF {
+            self.buffer.pop();
+        }
+    }
+
+    /*
+        Terminate the codeword with the predictable termination (ERTERM), which only outputs the
+        bits needed by the decoder, so that a decoder can detect errors in the codeword.
+    */
+    pub fn flush_predictable(&mut self) {
+        let mut k = 11 - self.ct as i32 + 1;
+
+        while k > 0 {
+            self.c <<= self.ct;
+            self.ct = 0;
+            self.byte_out();
+            k -= self.ct as i32;
+        }
+
+        // The byte being built is not part of the codeword, but a carry may still reach the last one
+        if self.last_byte() != 0xFF {
+            self.byte_out();
+        }
+        self.buffer.pop();
+    }
+
+    /*
+        Get the number of bytes output so far (the last byte can still change with a carry).
+    */
+    pub fn get_length(&self) -> usize { self.buffer.len() - 1 }
+
+    /*
+        Get the bytes output so far.
+    */
+    pub fn get_bytes(&self) -> &[u8] { &self.buffer[1..] }
+}
+
+/*
+    MQ decoder, reading a codeword from a slice of bytes.
+    Past the end of the codeword, the decoder reads 0xFF bytes (as if it had reached a marker).
+*/
+pub struct MqDecoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    a: u32,
+    c: u32,
+    ct: u32,
+    contexts: [Context; CONTEXT_COUNT],
+}
+
+impl<'a> MqDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        let mut decoder = MqDecoder {data, position: 0, a: 0x8000, c: 0, ct: 0, contexts: initial_contexts()};
+        decoder.restart(data);
+
+        decoder
+    }
+
+    /*
+        Start decoding a new codeword (after a termination), keeping the contexts.
+    */
+    pub fn restart(&mut self, data: &'a [u8]) {
+        self.data = data;
+        self.position = 0;
+        self.a = 0x8000;
+        self.c = (self.byte(0) as u32) << 16;
+        self.byte_in();
+        self.c <<= 7;
+        self.ct -= 7;
+    }
+
+    /*
+        Set every context back to its initial state.
+    */
+    pub fn reset_contexts(&mut self) {
+        self.contexts = initial_contexts();
+    }
+
+    fn byte(&self, position: usize) -> u8 {
+        *self.data.get(position).unwrap_or(&0xFF)
+    }
+
+    /*
+        Read the next byte into the code register, skipping the stuffed bit after a 0xFF.
+        A marker (0xFF followed by a byte above 0x8F) is not read: 1 bits are fed instead.
+    */
+    fn byte_in(&mut self) {
+        if self.byte(self.position) == 0xFF {
+            if self.byte(self.position + 1) > 0x8F {
+                self.c += 0xFF00;
+                self.ct = 8;
+            } else {
+                self.position += 1;
+                self.c += (self.byte(self.position) as u32) << 9;
+                self.ct = 7;
+            }
+        } else {
+            self.position += 1;
+            self.c += (self.byte(self.position) as u32) << 8;
+            self.ct = 8;
+        }
+    }
+
+    /*
+        Decode a decision (0 or 1) in the given context.
+    */
+    pub fn decode(&mut self, context: usize) -> u8 {
+        let Context {index, mps} = self.contexts[context];
+        let state = &STATES[index];
+        self.a -= state.qe;
+
+        if (self.c >> 16) < state.qe {
+            // LPS sub-interval (or MPS one when it is smaller): exchange
+            let bit = if self.a < state.qe {
+                self.contexts[context].index = state.nmps;
+                mps
+            } else {
+                if state.switch {
+                    self.contexts[context].mps = 1 - mps;
+                }
+                self.contexts[context].index = state.nlps;
+                1 - mps
+            };
+            self.a = state.qe;
+            self.renormalize();
+            bit
+        } else {
+            self.c -= state.qe << 16;
+            if self.a & 0x8000 == 0 {
+                let bit = if self.a < state.qe {
+                    if state.switch {
+                        self.contexts[context].mps = 1 - mps;
+                    }
+                    self.contexts[context].index = state.nlps;
+                    1 - mps
+                } else {
+                    self.contexts[context].index = state.nmps;
+                    mps
+                };
+                self.renormalize();
+                bit
+            } else {
+                mps
+            }
+        }
+    }
+
+    fn renormalize(&mut self) {
+        loop {
+            if self.ct == 0 {
+                self.byte_in();
+            }
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.a & 0x8000 != 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+        Example of the MQ coder (J.10): 256 decisions coded in a context starting in state 0 with a
+        MPS of 0, and their codeword (the codeword given by the spec is followed by the marker 0xFFAC).
+    */
+    const EXAMPLE_DECISIONS: [u8; 32] = [
+        0x00, 0x02, 0x00, 0x51, 0x00, 0x00, 0x00, 0xC0, 0x03, 0x52, 0x87, 0x2A, 0xAA, 0xAA, 0xAA, 0xAA,
+        0x82, 0xC0, 0x20, 0x00, 0xFC, 0xD7, 0x9E, 0xF6, 0xBF, 0x7F, 0xED, 0x90, 0x4F, 0x46, 0xA3, 0xBF,
+    ];
+    const EXAMPLE_CODEWORD: [u8; 28] = [
+        0x84, 0xC7, 0x3B, 0xFC, 0xE1, 0xA1, 0x43, 0x04, 0x02, 0x20, 0x00, 0x00, 0x41, 0x0D,
+        0xBB, 0x86, 0xF4, 0x31, 0x7F, 0xFF, 0x88, 0xFF, 0x37, 0x47, 0x1A, 0xDB, 0x6A, 0xDF,
+    ];
+    // A context starting in state 0
+    const EXAMPLE_CONTEXT: usize = 1;
+
+    #[test]
+    fn example_codeword() {
+        let bits = EXAMPLE_DECISIONS.iter().flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+        let mut encoder = MqEncoder::new();
+        bits.clone().for_each(|bit| encoder.encode(bit, EXAMPLE_CONTEXT));
+        encoder.flush();
+        assert_eq!(encoder.get_bytes(), EXAMPLE_CODEWORD);
+
+        let mut decoder = MqDecoder::new(&EXAMPLE_CODEWORD);
+        assert!(bits.map(|bit| (bit, decoder.decode(EXAMPLE_CONTEXT))).all(|(bit, decoded)| bit == decoded));
+    }
+
+    /*
+        Pseudo-random decisions with their contexts, more or less skewed depending on the context
+        (from a linear congruential generator).
+    */
+    fn decisions(count: usize, seed: u32) -> Vec<(u8, usize)> {
+        let mut state = seed;
+        (0..count).map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let context = (state >> 8) as usize % CONTEXT_COUNT;
+            // The context gives the probability of a 1, from 1/32 to 19/32
+            let bit = ((state >> 16) % 32 <= context as u32) as u8;
+            (bit, context)
+        }).collect()
+    }
+
+    /*
+        Code the decisions in segments of the given length, each one terminated with the standard or
+        the predictable flush, and check that they are decoded back.
+    */
+    fn check_round_trip(segment_length: usize, predictable: bool) {
+        for seed in 0..8 {
+            let decisions = decisions(5000, seed);
+            let mut encoder = MqEncoder::new();
+            let mut ends = Vec::new();
+
+            for segment in decisions.chunks(segment_length) {
+                segment.iter().for_each(|&(bit, context)| encoder.encode(bit, context));
+                if predictable { encoder.flush_predictable(); } else { encoder.flush(); }
+                ends.push(encoder.get_length());
+                encoder.restart();
+            }
+
+            let data = encoder.get_bytes();
+            let mut decoder = MqDecoder::new(&data[..ends[0]]);
+            for (index, segment) in decisions.chunks(segment_length).enumerate() {
+                if index > 0 {
+                    decoder.restart(&data[ends[index - 1]..ends[index]]);
+                }
+                let decoded: Vec<u8> = segment.iter().map(|&(_, context)| decoder.decode(context)).collect();
+                assert!(segment.iter().zip(&decoded).all(|(&(bit, _), &decoded)| bit == decoded), "segment {} of seed {}", index, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() { check_round_trip(5000, false); }
+
+    #[test]
+    fn round_trip_in_segments() { check_round_trip(37, false); }
+
+    #[test]
+    fn predictable_round_trip() { check_round_trip(5000, true); }
+
+    #[test]
+    fn predictable_round_trip_in_segments() { check_round_trip(37, true); }
+
+    #[test]
+    fn reset_contexts() {
+        let decisions = decisions(2000, 42);
+        let mut encoder = MqEncoder::new();
+        for (index, &(bit, context)) in decisions.iter().enumerate() {
+            if index % 100 == 0 { encoder.reset_contexts(); }
+            encoder.encode(bit, context);
+        }
+        encoder.flush();
+
+        let mut decoder = MqDecoder::new(encoder.get_bytes());
+        for (index, &(bit, context)) in decisions.iter().enumerate() {
+            if index % 100 == 0 { decoder.reset_contexts(); }
+            assert_eq!(decoder.decode(context), bit, "decision {}", index);
+        }
+    }
+}
@@ -1,16 +1,23 @@
 use image::{Rgba, GenericImageView, DynamicImage, ImageBuffer};
-use nalgebra::{DMatrix, Matrix2};
-use crate::color::{Rgba as MyRgba, Yuv};
+use nalgebra::DMatrix;
+use crate::color::Rgba as MyRgba;
 use crate::pixel_type::PixelTrait;
-use crate::compress::Superpixel;
 use crate::conversion::ConvertPixel;
+use crate::codestream::Encoder;
+use crate::color_transform::{dc_level_shift, inverse_dc_level_shift, inverse_ict};
+use crate::decoder::read_codestream;
+use crate::jp2::{ColourSpace, is_jp2, read_colour_space};
+use crate::subsampling::{ChromaSiting, UpsamplingFilter, upsample};
+use crate::wavelet::Wavelet;
 
 /*
     Crate of my own image type with different pixel types.
 */
+#[derive(Clone)]
 pub struct Image<P> where P: PixelTrait + ConvertPixel + 'static, {
     width: u32,
     height: u32,
+    // One row of the matrix for each line of the image.
     data: DMatrix<P>,
 }
 
@@ -41,18 +48,111 @@ impl<P> Image<P> where P: PixelTrait + ConvertPixel {
                 let img_pixel: Rgba<u8> = image.get_pixel(x, y);
                 let pixel = MyRgba::new(img_pixel.0[0], img_pixel.0[1], img_pixel.0[2], img_pixel.0[3]);
                 let new_pixel = P::from_rgba(pixel);
-                data[(x as usize, y as usize)] = new_pixel;
+                data[(y as usize, x as usize)] = new_pixel;
             }
         }
 
         Image {width, height, data}
     }
 
+    /*
+        Create a new image from a JPEG 2000 codestream (.j2k) or JP2 file.
+        The components are read as red, green, blue and alpha (or grey and alpha for 1 or 2 components),
+        upsampled if they are subsampled, and scaled to 8 bits. The first three components are YCbCr
+        for the sYCC colour space, or for a raw codestream if some of them are subsampled.
+    */
+    pub fn from_jpeg2000(data: &[u8]) -> Self {
+        let codestream = read_codestream(data);
+        let mut components = codestream.decode();
+        let precisions: Vec<u8> = codestream.get_size().get_components().iter().map(|component| component.get_precision()).collect();
+        let (width, height) = (codestream.get_size().get_width(), codestream.get_size().get_height());
+
+        for (component, info) in components.iter_mut().zip(codestream.get_size().get_components()) {
+            let (dx, dy) = (info.get_dx() as usize, info.get_dy() as usize);
+            if dx > 1 || dy > 1 {
+                *component = upsample(component, width as usize, height as usize, dx, dy, UpsamplingFilter::Bilinear, ChromaSiting::CoSited);
+            }
+        }
+
+        let subsampled = codestream.get_size().get_components().iter().take(3).any(|info| info.get_dx() > 1 || info.get_dy() > 1);
+        let colour_space = if is_jp2(data) { read_colour_space(data) } else { None };
+        if components.len() >= 3 && colour_space.map_or(subsampled, |colour_space| colour_space == ColourSpace::Sycc) {
+            components[..3].iter_mut().zip(&precisions).for_each(|(component, &precision)| dc_level_shift(component, precision));
+            inverse_ict(&mut components);
+            components[..3].iter_mut().zip(&precisions).for_each(|(component, &precision)| inverse_dc_level_shift(component, precision));
+        }
+
+        let sample = |channel: usize, x: usize, y: usize| {
+            let max = ((1_u64 << precisions[channel]) - 1) as f32;
+            (components[channel][(y, x)] * 255. / max).round().clamp(0., 255.) as u8
+        };
+        Image::from_rgba_matrix(&DMatrix::from_fn(height as usize, width as usize, |y, x| {
+            match components.len() {
+                1 | 2 => {
+                    let grey = sample(0, x, y);
+                    MyRgba::new(grey, grey, grey, if components.len() == 2 { sample(1, x, y) } else { 255 })
+                },
+                count => MyRgba::new(sample(0, x, y), sample(1, x, y), sample(2, x, y), if count > 3 { sample(3, x, y) } else { 255 }),
+            }
+        }))
+    }
+
+    /*
+        Create a new image from the RGBA value of each pixel (one row of the matrix for each line).
+    */
+    pub fn from_rgba_matrix(pixels: &DMatrix<MyRgba<u8>>) -> Self {
+        Image {
+            width: pixels.ncols() as u32,
+            height: pixels.nrows() as u32,
+            data: pixels.map(P::from_rgba),
+        }
+    }
+
+    /*
+        Create a new image from one matrix per channel (each row of a matrix is a line of the image).
+    */
+    pub fn from_channel_matrices(matrices: &[DMatrix<P::T>]) -> Self {
+        let (height, width) = matrices[0].shape();
+        let mut image = Image::new(width as u32, height as u32);
+
+        for (channel, matrix) in matrices.iter().enumerate() {
+            image.set_channel_matrix(channel, matrix);
+        }
+
+        image
+    }
+
     /*
         Get and set pixels.
     */
-    pub fn get_pixel(&self, x: u32, y: u32) -> P { self.data[(x as usize, y as usize)] }
-    pub fn set_pixel(&mut self, x: u32, y: u32, value: &[P::T]) { self.data[(x as usize, y as usize)] = P::from_channels(value); }
+    pub fn get_pixel(&self, x: u32, y: u32) -> P { self.data[(y as usize, x as usize)] }
+    pub fn set_pixel(&mut self, x: u32, y: u32, value: &[P::T]) { self.data[(y as usize, x as usize)] = P::from_channels(value); }
+
+    /*
+        Set a single channel of a pixel, keeping the other channels.
+    */
+    pub fn set_channel(&mut self, x: u32, y: u32, channel: usize, value: P::T) {
+        let mut channels = self.get_pixel(x, y).channels();
+        channels[channel] = value;
+        self.set_pixel(x, y, channels.as_slice());
+    }
+
+    /*
+        Get and set one channel of the image as a matrix (each row of the matrix is a line of the image).
+    */
+    pub fn get_channel(&self, channel: usize) -> DMatrix<P::T> {
+        DMatrix::from_fn(self.height as usize, self.width as usize, |y, x| {
+            self.get_pixel(x as u32, y as u32).channels()[channel]
+        })
+    }
+
+    pub fn set_channel_matrix(&mut self, channel: usize, matrix: &DMatrix<P::T>) {
+        for x in 0..self.width {
+            for y in 0..self.height {
+                self.set_channel(x, y, channel, matrix[(y as usize, x as usize)]);
+            }
+        }
+    }
 
     /*
         Get the width and height of the image.
@@ -77,6 +177,20 @@ impl<P> Image<P> where P: PixelTrait + ConvertPixel {
         image.save(path).unwrap();
     }
 
+    /*
+        Save the image to a JPEG 2000 codestream (.j2k) with the given encoder.
+    */
+    pub fn save_j2k<W: Wavelet>(&self, path: &str, encoder: &Encoder<W>) {
+        std::fs::write(path, encoder.encode(self)).unwrap();
+    }
+
+    /*
+        Save the image to a JP2 file with the given encoder.
+    */
+    pub fn save_jp2<W: Wavelet>(&self, path: &str, encoder: &Encoder<W>) {
+        std::fs::write(path, encoder.encode_jp2(self)).unwrap();
+    }
+
     /*
         Convert a image to a 3 RGB channels images.
         (one channel for each image)
@@ -93,30 +207,6 @@ impl<P> Image<P> where P: PixelTrait + ConvertPixel {
         let b_image = Image {width: self.width, height: self.height, data: b_matrix};
         let c_image = Image {width: self.width, height: self.height, data: c_matrix};
 
-        return (a_image, b_image, c_image);
-    }
-}
-
-impl Image<Yuv<f32>> {
-    pub fn get_superpixels(&self, channel: usize) -> Vec<Superpixel<Yuv<f32>>> {
-        let mut superpixels: Vec<Superpixel<Yuv<f32>>> = Vec::new();
-
-        for x in (0..self.width).step_by(2) {
-            for y in (0..self.height).step_by(2) {
-                let slice = self.data.slice((x as usize, y as usize), ((x+2) as usize, (y+2) as usize));
-                let slice_convert = slice.map(|x| x.channels()[channel]);
-                let matrix = Matrix2::new(
-                    slice_convert[(0, 0)], 
-                    slice_convert[(0, 1)], 
-                    slice_convert[(1, 0)], 
-                    slice_convert[(1, 1)]
-                );
-
-                let superpixel = Superpixel::new(matrix);
-
-                superpixels.push(superpixel);
-            }
-        }
-        return superpixels;
+        (a_image, b_image, c_image)
     }
 }
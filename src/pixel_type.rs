@@ -1,5 +1,4 @@
 use std::fmt::Debug;
-use std::any::type_name;
 use crate::color::{YCbCr, Yuv, Rgb, Rgba};
 use nalgebra::Matrix3x1;
 
@@ -7,17 +6,6 @@ use nalgebra::Matrix3x1;
     Crate of my own type and trait to handle different pixel types.
 */
 
-/*
-    Enum of the different pixel types.
-*/
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum PixelType {
-    Rgb,
-    Rgba,
-    YCbCr,
-    Yuv,
-}
-
 /*
     Trait to handle different pixel types.
 */
@@ -26,11 +14,7 @@ pub trait PixelTrait : Copy + Clone + PartialEq + Debug {
     type T: Copy + PartialEq + Debug + 'static;
     // Number of channels of the pixel type.
     const CHANNEL_COUNT: u8;
-    // Type of the pixel.
-    const TYPE: PixelType;
 
-    // Get the number of channels of the pixel type.
-    fn channels_count() -> u8 { Self::CHANNEL_COUNT }
     // Get the channels of the pixel.
     fn channels(&self) -> Matrix3x1<Self::T>;
 
@@ -40,10 +24,6 @@ pub trait PixelTrait : Copy + Clone + PartialEq + Debug {
     // Create a pixel from its channels.
     fn from_channels(v: &[Self::T]) -> Self;
 
-    // Get the type of the pixel.
-    fn get_type() -> PixelType { Self::TYPE }
-    // Get the data type of channels.
-    fn get_data_type() -> String { format!("{:?}", type_name::<Self::T>()) }
     // Get the first channel of the pixel.
     fn get_first_channel(&self) -> Self::T {
         let m = self.channels();
@@ -59,11 +39,6 @@ pub trait PixelTrait : Copy + Clone + PartialEq + Debug {
         let m = self.channels();
         *m.index((2, 0))
     }
-    // Get the fourth channel of the pixel.
-    fn get_fourth_channel(&self) -> Self::T {
-        let m = self.channels();
-        *m.index((3, 0))
-    }
 
     fn from_one_channel(value: Self::T, channel: usize) -> Self {
         let p = Self::default_pixel();
@@ -83,13 +58,12 @@ pub trait PixelTrait : Copy + Clone + PartialEq + Debug {
 impl PixelTrait for Rgb<u8> {
     type T = u8;
     const CHANNEL_COUNT: u8 = 3;
-    const TYPE: PixelType = PixelType::Rgb;
 
     fn channels(&self) -> Matrix3x1<Self::T> {
         let r = self.get_red();
         let g = self.get_green();
         let b = self.get_blue();
-        return Matrix3x1::new(r, g, b);
+        Matrix3x1::new(r, g, b)
     }
 
     fn default_pixel() -> Self { 
@@ -113,14 +87,13 @@ impl PixelTrait for Rgb<u8> {
 impl PixelTrait for Rgba<u8> {
     type T = u8;
     const CHANNEL_COUNT: u8 = 4;
-    const TYPE: PixelType = PixelType::Rgba;
 
     fn channels(&self) -> Matrix3x1<Self::T> {
         let r = self.get_red();
         let g = self.get_green();
         let b = self.get_blue();
 
-        return Matrix3x1::new(r, g, b);
+        Matrix3x1::new(r, g, b)
     }
 
     fn default_pixel() -> Self { Rgba::new(0, 0, 0, 255) }
@@ -146,14 +119,13 @@ impl PixelTrait for Rgba<u8> {
 impl PixelTrait for YCbCr<u8> {
     type T = u8;
     const CHANNEL_COUNT: u8 = 3;
-    const TYPE: PixelType = PixelType::YCbCr;
 
     fn channels(&self) -> Matrix3x1<Self::T> { 
         let y = self.get_y();
         let cb = self.get_cb();
         let cr = self.get_cr();
 
-        return Matrix3x1::new(y, cb, cr);
+        Matrix3x1::new(y, cb, cr)
     }
 
     fn default_pixel() -> Self { YCbCr::new(0, 0, 0) }
@@ -175,14 +147,13 @@ impl PixelTrait for YCbCr<u8> {
 impl PixelTrait for Yuv<f32> {
     type T = f32;
     const CHANNEL_COUNT: u8 = 3;
-    const TYPE: PixelType = PixelType::Yuv;
 
     fn channels(&self) -> Matrix3x1<Self::T> { 
         let y = self.get_y();
         let u = self.get_u();
         let v = self.get_v();
 
-        return Matrix3x1::new(y, u, v);
+        Matrix3x1::new(y, u, v)
     }
 
     fn default_pixel() -> Self { Yuv::new(0., 0., 0.) }
@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+
+/*
+    Crate of the progression orders of the packets (B.12 of the spec).
+
+    Each packet is identified by its layer, resolution level, component and precinct. A progression
+    order nests the loops over these four dimensions, the first one being the outermost:
+    - LRCP (layer-resolution-component-position): quality progressive,
+    - RLCP (resolution-layer-component-position): resolution progressive,
+    - RPCL (resolution-position-component-layer): resolution progressive, each precinct being
+      complete before the next one,
+    - PCRL (position-component-resolution-layer): spatially progressive,
+    - CPRL (component-position-resolution-layer): component by component.
+    The loops over positions go over the precincts from top to bottom and left to right on the
+    reference grid, where the precincts of different components and resolutions meet (B.12.1.3).
+    A progression can be limited to some layers, resolutions and components, so that the progression
+    order changes in the codestream (POC marker segment); each packet is only sent the first time
+    a progression includes it.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressionOrder {
+    Lrcp,
+    Rlcp,
+    Rpcl,
+    Pcrl,
+    Cprl,
+}
+
+// Dimensions of the packets, in the order of their indices in a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    Layer,
+    Resolution,
+    Component,
+    Precinct,
+}
+
+impl ProgressionOrder {
+    pub fn get_code(&self) -> u8 {
+        match self {
+            ProgressionOrder::Lrcp => 0,
+            ProgressionOrder::Rlcp => 1,
+            ProgressionOrder::Rpcl => 2,
+            ProgressionOrder::Pcrl => 3,
+            ProgressionOrder::Cprl => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => ProgressionOrder::Lrcp,
+            1 => ProgressionOrder::Rlcp,
+            2 => ProgressionOrder::Rpcl,
+            3 => ProgressionOrder::Pcrl,
+            4 => ProgressionOrder::Cprl,
+            _ => panic!("Unknown progression order {}.", code),
+        }
+    }
+
+    /*
+        Get the dimensions of the loops, from the outermost to the innermost.
+    */
+    fn get_dimensions(&self) -> [Dimension; 4] {
+        use Dimension::*;
+        match self {
+            ProgressionOrder::Lrcp => [Layer, Resolution, Component, Precinct],
+            ProgressionOrder::Rlcp => [Resolution, Layer, Component, Precinct],
+            ProgressionOrder::Rpcl => [Resolution, Precinct, Component, Layer],
+            ProgressionOrder::Pcrl => [Precinct, Component, Resolution, Layer],
+            ProgressionOrder::Cprl => [Component, Precinct, Resolution, Layer],
+        }
+    }
+}
+
+// Indices of a packet: layer, resolution, component and precinct
+pub type Packet = [usize; 4];
+
+// Position of a precinct on the reference grid: y, then x
+pub type Position = (u64, u64);
+
+/*
+    Progression of the packets of the layers before layer_end, of the resolutions from
+    resolution_start to resolution_end and of the components from component_start to component_end
+    (the ends are excluded), in the given order.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progression {
+    order: ProgressionOrder,
+    layer_end: usize,
+    resolution_start: usize,
+    resolution_end: usize,
+    component_start: usize,
+    component_end: usize,
+}
+
+impl Progression {
+    pub fn new(order: ProgressionOrder, layer_end: usize, resolution_start: usize, resolution_end: usize, component_start: usize, component_end: usize) -> Self {
+        if resolution_start >= resolution_end || component_start >= component_end || layer_end == 0 {
+            panic!("A progression must include at least one layer, one resolution and one component.");
+        }
+        Progression {order, layer_end, resolution_start, resolution_end, component_start, component_end}
+    }
+
+    /*
+        Create the progression of all the packets in the given order.
+    */
+    pub fn whole(order: ProgressionOrder, layer_count: usize, resolution_count: usize, component_count: usize) -> Self {
+        Progression::new(order, layer_count, 0, resolution_count, 0, component_count)
+    }
+
+    pub fn get_order(&self) -> ProgressionOrder { self.order }
+    pub fn get_layer_end(&self) -> usize { self.layer_end }
+    pub fn get_resolution_start(&self) -> usize { self.resolution_start }
+    pub fn get_resolution_end(&self) -> usize { self.resolution_end }
+    pub fn get_component_start(&self) -> usize { self.component_start }
+    pub fn get_component_end(&self) -> usize { self.component_end }
+
+    /*
+        Visit the packets of the progression from the loop at the given depth, some indices of the
+        packet and the position being set by the outer loops. positions gives the positions of the
+        precincts of each resolution of each component, in the order of the precincts.
+    */
+    fn visit(&self, depth: usize, packet: &mut [Option<usize>; 4], position: Option<Position>, layer_count: usize, positions: &[Vec<Vec<Position>>], visitor: &mut dyn FnMut(Packet)) {
+        let [layer, resolution, component, _] = *packet;
+        if depth == 4 {
+            let (layer, resolution, component, position) = (layer.unwrap(), resolution.unwrap(), component.unwrap(), position.unwrap());
+            // The precincts of a resolution are in raster order, so their positions are sorted
+            if let Some(Ok(precinct)) = positions[component].get(resolution).map(|precincts| precincts.binary_search(&position)) {
+                visitor([layer, resolution, component, precinct]);
+            }
+            return;
+        }
+
+        // The loops over resolutions and positions go as far as the components and resolutions they are in allow
+        let components = match component {
+            Some(component) => component..component + 1,
+            None => self.component_start..self.component_end.min(positions.len()),
+        };
+        let resolutions = match resolution {
+            Some(resolution) => resolution..resolution + 1,
+            None => self.resolution_start..self.resolution_end,
+        };
+        let dimension = self.order.get_dimensions()[depth];
+
+        if dimension == Dimension::Precinct {
+            let mut precinct_positions: Vec<Position> = components.flat_map(|component| {
+                resolutions.clone().filter_map(move |resolution| positions[component].get(resolution)).flatten().copied()
+            }).collect();
+            precinct_positions.sort_unstable();
+            precinct_positions.dedup();
+
+            for position in precinct_positions {
+                self.visit(depth + 1, packet, Some(position), layer_count, positions, visitor);
+            }
+            return;
+        }
+
+        let range = match dimension {
+            Dimension::Layer => 0..self.layer_end.min(layer_count),
+            Dimension::Resolution => {
+                let count = components.map(|component| positions[component].len()).max().unwrap_or(0);
+                resolutions.start..resolutions.end.min(count)
+            },
+            _ => components,
+        };
+        for index in range {
+            packet[dimension as usize] = Some(index);
+            self.visit(depth + 1, packet, position, layer_count, positions, visitor);
+        }
+        packet[dimension as usize] = None;
+    }
+}
+
+/*
+    Get the sequence of the packets of a tile with the given number of layers and positions of the
+    precincts of each resolution of each component, following the progressions one after the other.
+*/
+pub fn packet_sequence(progressions: &[Progression], layer_count: usize, positions: &[Vec<Vec<Position>>]) -> Vec<Packet> {
+    let mut sent = HashSet::new();
+    let mut sequence = Vec::new();
+
+    for progression in progressions {
+        progression.visit(0, &mut [None; 4], None, layer_count, positions, &mut |packet| {
+            if sent.insert(packet) {
+                sequence.push(packet);
+            }
+        });
+    }
+
+    sequence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYER_COUNT: usize = 2;
+    const ORDERS: [ProgressionOrder; 5] = [ProgressionOrder::Lrcp, ProgressionOrder::Rlcp, ProgressionOrder::Rpcl, ProgressionOrder::Pcrl, ProgressionOrder::Cprl];
+
+    /*
+        Positions of the precincts of 3 resolutions of 2 components, the second one being subsampled
+        (its precincts are further apart on the reference grid).
+    */
+    fn positions() -> Vec<Vec<Vec<Position>>> {
+        vec![
+            vec![vec![(0, 0)], vec![(0, 0), (0, 16)], vec![(0, 0), (0, 8), (8, 0), (8, 8)]],
+            vec![vec![(0, 0)], vec![(0, 0)], vec![(0, 0), (0, 16), (16, 0), (16, 16)]],
+        ]
+    }
+
+    /*
+        Sequence of the packets with the loops of B.12.1, the loops over positions going over the
+        positions of all the precincts from top to bottom and left to right.
+    */
+    fn expected_sequence(order: ProgressionOrder, positions: &[Vec<Vec<Position>>]) -> Vec<Packet> {
+        let mut grid: Vec<Position> = positions.iter().flatten().flatten().copied().collect();
+        grid.sort_unstable();
+        grid.dedup();
+        let precinct = |resolution: usize, component: usize, position: &Position| {
+            positions[component][resolution].iter().position(|other| other == position)
+        };
+        let (resolutions, components) = (0..3, 0..positions.len());
+        let mut sequence = Vec::new();
+
+        match order {
+            ProgressionOrder::Lrcp => {
+                for layer in 0..LAYER_COUNT {
+                    for resolution in resolutions.clone() {
+                        for component in components.clone() {
+                            sequence.extend((0..positions[component][resolution].len()).map(|precinct| [layer, resolution, component, precinct]));
+                        }
+                    }
+                }
+            },
+            ProgressionOrder::Rlcp => {
+                for resolution in resolutions.clone() {
+                    for layer in 0..LAYER_COUNT {
+                        for component in components.clone() {
+                            sequence.extend((0..positions[component][resolution].len()).map(|precinct| [layer, resolution, component, precinct]));
+                        }
+                    }
+                }
+            },
+            ProgressionOrder::Rpcl => {
+                for resolution in resolutions {
+                    for position in &grid {
+                        for component in components.clone() {
+                            if let Some(precinct) = precinct(resolution, component, position) {
+                                sequence.extend((0..LAYER_COUNT).map(|layer| [layer, resolution, component, precinct]));
+                            }
+                        }
+                    }
+                }
+            },
+            ProgressionOrder::Pcrl => {
+                for position in &grid {
+                    for component in components.clone() {
+                        for resolution in resolutions.clone() {
+                            if let Some(precinct) = precinct(resolution, component, position) {
+                                sequence.extend((0..LAYER_COUNT).map(|layer| [layer, resolution, component, precinct]));
+                            }
+                        }
+                    }
+                }
+            },
+            ProgressionOrder::Cprl => {
+                for component in components {
+                    for position in &grid {
+                        for resolution in resolutions.clone() {
+                            if let Some(precinct) = precinct(resolution, component, position) {
+                                sequence.extend((0..LAYER_COUNT).map(|layer| [layer, resolution, component, precinct]));
+                            }
+                        }
+                    }
+                }
+            },
+        }
+
+        sequence
+    }
+
+    /*
+        Check that a sequence has every packet exactly once.
+    */
+    fn check_packets(sequence: &[Packet], positions: &[Vec<Vec<Position>>]) {
+        let count: usize = positions.iter().flatten().map(|precincts| precincts.len()).sum::<usize>() * LAYER_COUNT;
+        assert_eq!(sequence.len(), count);
+        assert_eq!(sequence.iter().collect::<HashSet<_>>().len(), count);
+    }
+
+    #[test]
+    fn progression_orders() {
+        let positions = positions();
+        for order in ORDERS {
+            let sequence = packet_sequence(&[Progression::whole(order, LAYER_COUNT, 3, 2)], LAYER_COUNT, &positions);
+            check_packets(&sequence, &positions);
+            assert_eq!(sequence, expected_sequence(order, &positions), "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn progression_order_changes() {
+        let positions = positions();
+        // The first layer of the lowest resolutions of the first component, then the rest
+        let progressions = [
+            Progression::new(ProgressionOrder::Rpcl, 1, 0, 2, 0, 1),
+            Progression::whole(ProgressionOrder::Cprl, LAYER_COUNT, 3, 2),
+        ];
+        let sequence = packet_sequence(&progressions, LAYER_COUNT, &positions);
+        check_packets(&sequence, &positions);
+        assert_eq!(sequence[..3], [[0, 0, 0, 0], [0, 1, 0, 0], [0, 1, 0, 1]]);
+
+        let rest: Vec<Packet> = expected_sequence(ProgressionOrder::Cprl, &positions).into_iter().filter(|packet| !sequence[..3].contains(packet)).collect();
+        assert_eq!(sequence[3..], rest);
+    }
+}
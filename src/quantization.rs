@@ -0,0 +1,415 @@
+use nalgebra::DMatrix;
+use crate::subband::{Decomposition, Orientation, Subband};
+use crate::wavelet::{Wavelet, synthesis_norm};
+
+/*
+    Crate of the JPEG 2000 dead-zone uniform scalar quantization of the subbands.
+
+    A coefficient y of a subband b is quantized with the step size D(b) into
+        q = sign(y) * floor(|y| / D(b))
+    so every coefficient smaller than the step size is set to 0 (the dead zone is twice as large
+    as the other intervals). The index q is stored in sign-magnitude form, on M(b) magnitude bits.
+*/
+
+/*
+    Log2 of the gain of the subbands of each orientation (0 for LL, 1 for HL and LH, 2 for HH).
+*/
+pub fn log2_gain(orientation: Orientation) -> u8 {
+    match orientation {
+        Orientation::LL => 0,
+        Orientation::HL | Orientation::LH => 1,
+        Orientation::HH => 2,
+    }
+}
+
+/*
+    Nominal dynamic range R(b) of a subband, in bits: the precision of the component samples
+    plus the gain of the subband.
+*/
+pub fn dynamic_range(precision: u8, orientation: Orientation) -> u8 {
+    precision + log2_gain(orientation)
+}
+
+/*
+    Step size of a subband as signalled in the codestream, with an exponent e (5 bits) and
+    a mantissa m (11 bits) relative to the dynamic range R(b) of the subband:
+        D(b) = 2^(R(b) - e) * (1 + m / 2^11)
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepSize {
+    exponent: u8,
+    mantissa: u16,
+}
+
+impl StepSize {
+    pub fn new(exponent: u8, mantissa: u16) -> Self {
+        if exponent > 31 || mantissa > 2047 {
+            panic!("Step size exponent must fit on 5 bits and mantissa on 11 bits.");
+        }
+        StepSize {exponent, mantissa}
+    }
+
+    /*
+        Get the closest step size that can be signalled for the given value.
+    */
+    pub fn from_value(step: f32, dynamic_range: u8) -> Self {
+        if !(step.is_finite() && step > 0.) {
+            panic!("The step size must be positive, not {}.", step);
+        }
+        let log2 = step.log2().floor();
+        let mut mantissa = ((step / log2.exp2() - 1.) * 2048.).round() as i32;
+        let mut exponent = dynamic_range as i32 - log2 as i32;
+
+        // Rounding up the mantissa can reach the next power of 2
+        if mantissa == 2048 {
+            mantissa = 0;
+            exponent -= 1;
+        }
+
+        if !(0..=31).contains(&exponent) {
+            panic!("The step size {} can not be signalled with a dynamic range of {} bits.", step, dynamic_range);
+        }
+        StepSize::new(exponent as u8, mantissa as u16)
+    }
+
+    pub fn get_exponent(&self) -> u8 { self.exponent }
+    pub fn get_mantissa(&self) -> u16 { self.mantissa }
+
+    pub fn get_value(&self, dynamic_range: u8) -> f32 {
+        (dynamic_range as f32 - self.exponent as f32).exp2() * (1. + self.mantissa as f32 / 2048.)
+    }
+}
+
+/*
+    Quantization styles of the QCD and QCC marker segments, deciding which step sizes are signalled:
+    - NoQuantization: the step size is 1 (reversible mode), only the exponents are signalled,
+    - ScalarDerived: only the step size of the LL subband is signalled, the others are derived
+      from it with their decomposition level,
+    - ScalarExpounded: the step size of every subband is signalled.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizationStyle {
+    NoQuantization,
+    ScalarDerived,
+    ScalarExpounded,
+}
+
+impl QuantizationStyle {
+    /*
+        Get the style from and to its code in the marker segments.
+    */
+    pub fn get_code(&self) -> u8 {
+        match self {
+            QuantizationStyle::NoQuantization => 0,
+            QuantizationStyle::ScalarDerived => 1,
+            QuantizationStyle::ScalarExpounded => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => QuantizationStyle::NoQuantization,
+            1 => QuantizationStyle::ScalarDerived,
+            2 => QuantizationStyle::ScalarExpounded,
+            _ => panic!("Unknown quantization style {}.", code),
+        }
+    }
+}
+
+/*
+    Get the step sizes of all the subbands of a decomposition on the given number of levels
+    (in resolution order) from the ones signalled with the given style.
+    In the derived style, a subband of decomposition level n gets the exponent e0 - NL + n and
+    the mantissa m0 of the LL subband, so its step size doubles from one level to the finer one
+    (e0 must be at least NL - 1, so that every exponent can be signalled).
+*/
+pub fn expand_step_sizes(style: QuantizationStyle, signalled: &[StepSize], level_count: usize) -> Vec<StepSize> {
+    let subband_count = 3 * level_count + 1;
+
+    match style {
+        QuantizationStyle::ScalarDerived => {
+            let ll = signalled[0];
+            let mut step_sizes = vec![ll];
+            for level in (1..=level_count).rev() {
+                let exponent = ll.get_exponent() as i32 - level_count as i32 + level as i32;
+                if exponent < 0 {
+                    panic!("The exponent {} of the LL subband can not give the step sizes of {} levels in the derived style.", ll.get_exponent(), level_count);
+                }
+                let step_size = StepSize::new(exponent as u8, ll.get_mantissa());
+                step_sizes.extend_from_slice(&[step_size; 3]);
+            }
+            step_sizes
+        },
+        _ => {
+            if signalled.len() < subband_count {
+                panic!("{} step sizes are signalled for {} subbands.", signalled.len(), subband_count);
+            }
+            signalled[..subband_count].to_vec()
+        },
+    }
+}
+
+/*
+    Quantization indices of a subband, with the step size used and the number of magnitude bits
+    M(b) = G + e - 1 (where G is the number of guard bits) needed to code them. The indices decoded
+    from truncated code-blocks miss their lowest bit-planes, whose number is kept for each index.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedSubband {
+    orientation: Orientation,
+    level: usize,
+    origin: (u32, u32),
+    step_size: StepSize,
+    magnitude_bits: u8,
+    coefficients: DMatrix<i32>,
+    missing_planes: DMatrix<u8>,
+}
+
+impl QuantizedSubband {
+    /*
+        Create a subband with all the bit-planes of its indices.
+    */
+    pub fn new(orientation: Orientation, level: usize, origin: (u32, u32), step_size: StepSize, magnitude_bits: u8, coefficients: DMatrix<i32>) -> Self {
+        let missing_planes = DMatrix::zeros(coefficients.nrows(), coefficients.ncols());
+        QuantizedSubband {orientation, level, origin, step_size, magnitude_bits, coefficients, missing_planes}
+    }
+
+    pub fn get_orientation(&self) -> Orientation { self.orientation }
+    pub fn get_level(&self) -> usize { self.level }
+    pub fn get_origin(&self) -> (u32, u32) { self.origin }
+    pub fn get_width(&self) -> u32 { self.coefficients.ncols() as u32 }
+    pub fn get_height(&self) -> u32 { self.coefficients.nrows() as u32 }
+    pub fn get_step_size(&self) -> StepSize { self.step_size }
+    pub fn get_magnitude_bits(&self) -> u8 { self.magnitude_bits }
+    pub fn get_coefficients(&self) -> &DMatrix<i32> { &self.coefficients }
+    pub fn get_missing_planes(&self) -> &DMatrix<u8> { &self.missing_planes }
+
+    pub fn set_missing_planes(&mut self, missing_planes: DMatrix<u8>) {
+        if missing_planes.shape() != self.coefficients.shape() {
+            panic!("The missing bit-planes must be given for each index of the subband.");
+        }
+        self.missing_planes = missing_planes;
+    }
+}
+
+/*
+    Quantizer of the subbands of a component with the given precision (bits per sample).
+    - With the expounded style, the step size of each subband is the base step size divided by the
+      synthesis norm of the subband, so that the error of every subband weighs the same in the image.
+    - With the derived style, only the step size of the LL subband is computed this way.
+    - Without quantization (reversible mode), the coefficients are integers and are kept as they are.
+    The reconstruction offset r (between 0 and 1) places the dequantized values in their interval:
+    y = sign(q) * (|q| + r * 2^p) * D(b) for q != 0, where p is the number of bit-planes missing from
+    the index q (E.1.1.2). Without quantization, the complete indices are exact and only the
+    truncated ones get the offset.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantizer {
+    precision: u8,
+    style: QuantizationStyle,
+    guard_bits: u8,
+    base_step: f32,
+    reconstruction_offset: f32,
+}
+
+impl Quantizer {
+    pub fn new(precision: u8, base_step: f32) -> Self {
+        Quantizer {precision, style: QuantizationStyle::ScalarExpounded, guard_bits: 2, base_step, reconstruction_offset: 0.5}
+    }
+
+    /*
+        Create a quantizer for the reversible mode (no quantization).
+    */
+    pub fn reversible(precision: u8) -> Self {
+        Quantizer {style: QuantizationStyle::NoQuantization, ..Quantizer::new(precision, 1.)}
+    }
+
+    #[allow(dead_code)]
+    pub fn get_precision(&self) -> u8 { self.precision }
+    pub fn get_style(&self) -> QuantizationStyle { self.style }
+    pub fn get_guard_bits(&self) -> u8 { self.guard_bits }
+
+    pub fn set_style(&mut self, style: QuantizationStyle) { self.style = style; }
+
+    pub fn set_guard_bits(&mut self, guard_bits: u8) {
+        if guard_bits > 7 {
+            panic!("The number of guard bits must fit on 3 bits.");
+        }
+        self.guard_bits = guard_bits;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_reconstruction_offset(&mut self, offset: f32) {
+        if !(0. ..1.).contains(&offset) {
+            panic!("The reconstruction offset must be between 0 and 1.");
+        }
+        self.reconstruction_offset = offset;
+    }
+
+    /*
+        Get the step size computed for a subband of the given orientation and decomposition level.
+    */
+    fn compute_step_size<W: Wavelet>(&self, orientation: Orientation, level: usize) -> StepSize {
+        let norm = synthesis_norm::<W>(level, orientation.get_x() == 1) * synthesis_norm::<W>(level, orientation.get_y() == 1);
+        StepSize::from_value(self.base_step / norm, dynamic_range(self.precision, orientation))
+    }
+
+    /*
+        Get the step sizes to signal for a decomposition on the given number of levels:
+        one for each subband (in resolution order), or only the one of the LL subband in derived style.
+    */
+    pub fn get_signalled_step_sizes<W: Wavelet>(&self, level_count: usize) -> Vec<StepSize> {
+        let mut subbands = vec![(Orientation::LL, level_count)];
+        for level in (1..=level_count).rev() {
+            subbands.extend_from_slice(&[(Orientation::HL, level), (Orientation::LH, level), (Orientation::HH, level)]);
+        }
+
+        match self.style {
+            QuantizationStyle::NoQuantization => subbands.iter().map(|(orientation, _)| {
+                StepSize::new(dynamic_range(self.precision, *orientation), 0)
+            }).collect(),
+            QuantizationStyle::ScalarDerived => vec![self.compute_step_size::<W>(Orientation::LL, level_count)],
+            QuantizationStyle::ScalarExpounded => subbands.iter().map(|(orientation, level)| {
+                self.compute_step_size::<W>(*orientation, *level)
+            }).collect(),
+        }
+    }
+
+    /*
+        Get the step sizes of all the subbands of a decomposition on the given number of levels.
+    */
+    pub fn get_step_sizes<W: Wavelet>(&self, level_count: usize) -> Vec<StepSize> {
+        expand_step_sizes(self.style, &self.get_signalled_step_sizes::<W>(level_count), level_count)
+    }
+
+    /*
+        Get the number of magnitude bits of the indices quantized with the given step size.
+    */
+    pub fn get_magnitude_bits(&self, step_size: StepSize) -> u8 {
+        (self.guard_bits + step_size.get_exponent()).saturating_sub(1)
+    }
+
+    /*
+        Quantize the coefficients of a subband with the given step size.
+        Indices too large for the magnitude bits (not enough guard bits) are clipped.
+    */
+    pub fn quantize_subband(&self, subband: &Subband, step_size: StepSize) -> QuantizedSubband {
+        let orientation = subband.get_orientation();
+        let magnitude_bits = self.get_magnitude_bits(step_size);
+        let max_magnitude = ((1_i64 << magnitude_bits) - 1).min(i32::MAX as i64) as f32;
+        let step = match self.style {
+            QuantizationStyle::NoQuantization => 1.,
+            _ => step_size.get_value(dynamic_range(self.precision, orientation)),
+        };
+
+        let coefficients = subband.get_coefficients().map(|y| {
+            let magnitude = (y.abs() / step).floor().min(max_magnitude);
+            if y < 0. { -magnitude as i32 } else { magnitude as i32 }
+        });
+
+        QuantizedSubband::new(orientation, subband.get_level(), subband.get_origin(), step_size, magnitude_bits, coefficients)
+    }
+
+    /*
+        Get the coefficients of a subband back from their quantization indices.
+    */
+    pub fn dequantize_subband(&self, subband: &QuantizedSubband) -> DMatrix<f32> {
+        let step = match self.style {
+            QuantizationStyle::NoQuantization => 1.,
+            _ => subband.get_step_size().get_value(dynamic_range(self.precision, subband.get_orientation())),
+        };
+        let offset = |missing_planes: u8| match (self.style, missing_planes) {
+            (QuantizationStyle::NoQuantization, 0) => 0.,
+            (_, planes) => self.reconstruction_offset * (planes as f32).exp2(),
+        };
+
+        subband.get_coefficients().zip_map(subband.get_missing_planes(), |q, planes| match q {
+            0 => 0.,
+            q if q > 0 => (q as f32 + offset(planes)) * step,
+            q => (q as f32 - offset(planes)) * step,
+        })
+    }
+
+    /*
+        Quantize every subband of a decomposition, in resolution order.
+    */
+    pub fn quantize<W: Wavelet>(&self, decomposition: &Decomposition<W>) -> Vec<QuantizedSubband> {
+        let step_sizes = self.get_step_sizes::<W>(decomposition.get_level_count());
+
+        decomposition.subbands().zip(step_sizes).map(|(subband, step_size)| {
+            self.quantize_subband(subband, step_size)
+        }).collect()
+    }
+
+    /*
+        Replace the coefficients of a decomposition by the dequantized subbands (in resolution order).
+    */
+    pub fn dequantize<W: Wavelet>(&self, subbands: &[QuantizedSubband], decomposition: &mut Decomposition<W>) {
+        for (subband, quantized) in decomposition.subbands_mut().zip(subbands.iter()) {
+            subband.set_coefficients(self.dequantize_subband(quantized));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wavelet::Cdf97;
+
+    #[test]
+    fn step_size_from_value() {
+        for step in [0.125, 0.7, 1., 3.3, 100.] {
+            let value = StepSize::from_value(step, 10).get_value(10);
+            assert!((value - step).abs() <= step / 2048., "{} signalled as {}", step, value);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "can not be signalled")]
+    fn step_size_out_of_range() {
+        StepSize::from_value(1e-9, 10);
+    }
+
+    #[test]
+    fn signalled_step_sizes() {
+        let mut quantizer = Quantizer::new(8, 4.);
+        let expounded = quantizer.get_signalled_step_sizes::<Cdf97>(3);
+        quantizer.set_style(QuantizationStyle::ScalarDerived);
+        let derived = quantizer.get_signalled_step_sizes::<Cdf97>(3);
+        assert_eq!(expounded.len(), 10);
+        assert_eq!(derived, expounded[..1]);
+
+        // The derived step sizes keep the mantissa of LL, and double from one level to the finer one
+        let step_sizes = expand_step_sizes(QuantizationStyle::ScalarDerived, &derived, 3);
+        assert_eq!(step_sizes, quantizer.get_step_sizes::<Cdf97>(3));
+        let ll = derived[0];
+        let exponents: Vec<u8> = step_sizes.iter().map(|step_size| step_size.get_exponent()).collect();
+        let e0 = ll.get_exponent();
+        assert_eq!(exponents, [e0, e0, e0, e0, e0 - 1, e0 - 1, e0 - 1, e0 - 2, e0 - 2, e0 - 2]);
+        assert!(step_sizes.iter().all(|step_size| step_size.get_mantissa() == ll.get_mantissa()));
+    }
+
+    #[test]
+    #[should_panic(expected = "can not give the step sizes of 5 levels in the derived style")]
+    fn derived_exponent_out_of_range() {
+        expand_step_sizes(QuantizationStyle::ScalarDerived, &[StepSize::new(3, 0)], 5);
+    }
+
+    #[test]
+    fn dequantization_offset() {
+        let step_size = StepSize::from_value(2., dynamic_range(8, Orientation::HH));
+        let coefficients = DMatrix::from_row_slice(1, 4, &[0, 3, -3, 8]);
+        let mut subband = QuantizedSubband::new(Orientation::HH, 1, (0, 0), step_size, 10, coefficients);
+        subband.set_missing_planes(DMatrix::from_row_slice(1, 4, &[2, 0, 0, 2]));
+
+        // The offset is r * 2^p index units, once
+        let mut quantizer = Quantizer::new(8, 1.);
+        assert_eq!(quantizer.dequantize_subband(&subband), DMatrix::from_row_slice(1, 4, &[0., 7., -7., 20.]));
+        quantizer.set_reconstruction_offset(0.);
+        assert_eq!(quantizer.dequantize_subband(&subband), DMatrix::from_row_slice(1, 4, &[0., 6., -6., 16.]));
+
+        // Without quantization, only the truncated indices get it
+        let reversible = Quantizer::reversible(8);
+        assert_eq!(reversible.dequantize_subband(&subband), DMatrix::from_row_slice(1, 4, &[0., 3., -3., 10.]));
+    }
+}
@@ -0,0 +1,371 @@
+use std::ops::Range;
+use crate::tier1::CodedSubband;
+
+/*
+    Crate of the rate control: the post-compression rate-distortion optimisation (PCRD-opt) choosing
+    how many coding passes of each code-block go into each quality layer.
+
+    The truncation points of a code-block (after each coding pass) give its length and the decrease
+    of distortion, weighted by the squares of the step size and of the synthesis norm of its subband
+    (and by the gain of its component through the inverse component transform) so that all the
+    code-blocks can be compared. Only the points on the convex hull of each code-block are useful:
+    they have decreasing rate-distortion slopes (distortion decrease per byte). For a slope
+    threshold, each code-block is truncated at its last point with a slope above the threshold,
+    which gives the least distortion for the resulting size. A layer is the threshold meeting its
+    target: the lower the threshold, the more passes, bytes and quality. A size limit then raises the
+    thresholds of the layers to the lowest one keeping the whole output within a budget of bytes.
+*/
+
+/*
+    Target of a quality layer: a rate in bits per pixel (counting the whole codestream), or a PSNR
+    in decibels (estimated from the distortion of the coding passes left out, weighted up to the samples
+    of the image).
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerTarget {
+    BitsPerPixel(f64),
+    Psnr(f64),
+}
+
+/*
+    Limit of the size of the whole output: a number of bytes, or a rate in bits per pixel.
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeLimit {
+    Bytes(usize),
+    BitsPerPixel(f64),
+}
+
+impl SizeLimit {
+    /*
+        Get the number of bytes allowed for an image with the given number of pixels.
+    */
+    pub fn get_budget(&self, pixel_count: usize) -> usize {
+        match *self {
+            SizeLimit::Bytes(bytes) => bytes,
+            SizeLimit::BitsPerPixel(rate) => (rate * pixel_count as f64 / 8.).floor() as usize,
+        }
+    }
+}
+
+// Truncation point of a code-block on its convex hull
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TruncationPoint {
+    pass_count: usize,
+    length: usize,
+    distortion: f64,
+    slope: f64,
+}
+
+/*
+    Truncation points of all the code-blocks (of the components, then of their subbands, then of
+    their code-blocks in order), with the number of passes and the total distortion decrease of each one.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct TruncationPoints {
+    hulls: Vec<Vec<TruncationPoint>>,
+    pass_counts: Vec<usize>,
+    distortions: Vec<f64>,
+}
+
+impl TruncationPoints {
+    /*
+        Get the truncation points of the code-blocks of the components, with the weight of the
+        distortion of each subband.
+    */
+    pub fn new(components: &[Vec<CodedSubband>], weights: &[Vec<f64>]) -> Self {
+        let mut points = TruncationPoints {hulls: Vec::new(), pass_counts: Vec::new(), distortions: Vec::new()};
+
+        for (subbands, weights) in components.iter().zip(weights) {
+            for (subband, &weight) in subbands.iter().zip(weights) {
+                for block in subband.get_blocks() {
+                    let start = TruncationPoint {pass_count: 0, length: 0, distortion: 0., slope: f64::INFINITY};
+                    let mut hull = vec![start];
+                    let mut distortion = 0.;
+
+                    for (index, pass) in block.get_passes().iter().enumerate() {
+                        distortion += pass.get_distortion() * weight;
+                        let length = block.get_length(index + 1);
+
+                        // The previous points under the segment to the new point leave the hull
+                        loop {
+                            let last = *hull.last().unwrap();
+                            let gain = distortion - last.distortion;
+                            if gain <= 0. {
+                                break;
+                            }
+                            let slope = match length - last.length {
+                                0 => f64::INFINITY,
+                                bytes => gain / bytes as f64,
+                            };
+                            if hull.len() > 1 && slope >= last.slope {
+                                hull.pop();
+                                continue;
+                            }
+                            hull.push(TruncationPoint {pass_count: index + 1, length, distortion, slope});
+                            break;
+                        }
+                    }
+
+                    points.hulls.push(hull);
+                    points.pass_counts.push(block.get_passes().len());
+                    points.distortions.push(distortion);
+                }
+            }
+        }
+
+        points
+    }
+
+    /*
+        Get the possible slope thresholds from the highest to the lowest: the slopes of the hulls,
+        then 0 for all the passes of every code-block.
+    */
+    pub fn get_thresholds(&self) -> Vec<f64> {
+        let mut slopes: Vec<f64> = self.hulls.iter().flat_map(|hull| hull[1..].iter().map(|point| point.slope)).collect();
+        slopes.sort_by(|a, b| b.total_cmp(a));
+        slopes.dedup();
+        slopes.push(0.);
+        slopes
+    }
+
+    /*
+        Get the number of passes of each code-block kept for a slope threshold.
+    */
+    pub fn get_pass_counts(&self, threshold: f64) -> Vec<usize> {
+        self.hulls.iter().zip(&self.pass_counts).map(|(hull, &count)| match threshold > 0. {
+            true => hull.iter().rev().find(|point| point.slope >= threshold).map_or(0, |point| point.pass_count),
+            false => count,
+        }).collect()
+    }
+
+    /*
+        Get the distortion left by the passes which are not kept for a slope threshold.
+    */
+    pub fn get_distortion(&self, threshold: f64) -> f64 {
+        self.hulls.iter().zip(&self.distortions).map(|(hull, &total)| match threshold > 0. {
+            true => total - hull.iter().rev().find(|point| point.slope >= threshold).map_or(0., |point| point.distortion),
+            false => 0.,
+        }).sum()
+    }
+}
+
+/*
+    Set the layers of the code-blocks from the slope threshold of each layer (decreasing).
+*/
+pub fn set_layers(components: &mut [Vec<CodedSubband>], points: &TruncationPoints, thresholds: &[f64]) {
+    let pass_counts: Vec<Vec<usize>> = thresholds.iter().map(|&threshold| points.get_pass_counts(threshold)).collect();
+    let blocks = components.iter_mut().flat_map(|subbands| subbands.iter_mut()).flat_map(|subband| subband.get_blocks_mut().iter_mut());
+
+    for (index, block) in blocks.enumerate() {
+        block.set_layers(pass_counts.iter().map(|counts| counts[index]).collect());
+    }
+}
+
+/*
+    Find the first index of a range for which a test becomes true, the test being false before it
+    and true after it (None if it is false on the whole range).
+*/
+pub fn bisect(range: Range<usize>, mut test: impl FnMut(usize) -> bool) -> Option<usize> {
+    let (mut low, mut high) = (range.start, range.end);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if test(middle) { high = middle; } else { low = middle + 1; }
+    }
+    (low < range.end).then_some(low)
+}
+
+/*
+    Set the layers of the code-blocks of the components to meet the targets, which must have
+    increasing rates or PSNRs, and get their slope thresholds. size gives the number of bytes of the codestream with the given
+    number of layers set in the code-blocks. The samples count the samples of the image (of all its
+    channels), whose maximum value is peak.
+*/
+pub fn allocate_layers(
+    components: &mut [Vec<CodedSubband>],
+    points: &TruncationPoints,
+    targets: &[LayerTarget],
+    pixel_count: usize,
+    sample_count: usize,
+    peak: f64,
+    mut size: impl FnMut(&[Vec<CodedSubband>], usize) -> usize,
+) -> Vec<f64> {
+    let candidates = points.get_thresholds();
+    let mut thresholds: Vec<f64> = Vec::new();
+    // Index of the threshold of the last layer (the layers can only add passes)
+    let mut start = 0;
+
+    for target in targets {
+        let index = match *target {
+            LayerTarget::BitsPerPixel(rate) => {
+                let budget = (rate * pixel_count as f64 / 8.).floor() as usize;
+                // The first threshold going over the budget, the one before being the last within it
+                let over = bisect(start..candidates.len(), |index| {
+                    let mut layers = thresholds.clone();
+                    layers.push(candidates[index]);
+                    set_layers(components, points, &layers);
+                    size(components, layers.len()) > budget
+                });
+                match over {
+                    Some(index) => index.checked_sub(1).filter(|&index| index >= start),
+                    None => Some(candidates.len() - 1),
+                }
+            },
+            LayerTarget::Psnr(psnr) => {
+                let distortion = peak * peak * sample_count as f64 / 10_f64.powf(psnr / 10.);
+                bisect(start..candidates.len(), |index| points.get_distortion(candidates[index]) <= distortion)
+                    .or(Some(candidates.len() - 1))
+            },
+        };
+
+        // Without any threshold meeting the target, the layer adds nothing
+        match index {
+            Some(index) => {
+                thresholds.push(candidates[index]);
+                start = index;
+            },
+            None => thresholds.push(thresholds.last().copied().unwrap_or(f64::INFINITY)),
+        }
+    }
+
+    set_layers(components, points, &thresholds);
+    thresholds
+}
+
+/*
+    Set the layers of the code-blocks of the components from the slope thresholds of the layers,
+    raised as little as possible so that the size (as given by size, see allocate_layers) is within
+    the budget, and get the thresholds used.
+*/
+pub fn limit_layers(
+    components: &mut [Vec<CodedSubband>],
+    points: &TruncationPoints,
+    thresholds: &[f64],
+    budget: usize,
+    mut size: impl FnMut(&[Vec<CodedSubband>], usize) -> usize,
+) -> Vec<f64> {
+    // Above the highest slope, no pass is kept
+    let mut candidates = points.get_thresholds();
+    candidates.insert(0, f64::INFINITY);
+    let raise = |index: usize| -> Vec<f64> {
+        thresholds.iter().map(|&threshold| threshold.max(candidates[index])).collect()
+    };
+
+    // The first threshold going over the budget, the one before being the last within it
+    let over = bisect(0..candidates.len(), |index| {
+        let layers = raise(index);
+        set_layers(components, points, &layers);
+        size(components, layers.len()) > budget
+    });
+    let layers = match over {
+        Some(0) => panic!("A budget of {} bytes is too small even without any coding pass.", budget),
+        Some(index) => raise(index - 1),
+        None => raise(candidates.len() - 1),
+    };
+
+    set_layers(components, points, &layers);
+    layers
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::DMatrix;
+    use super::*;
+    use crate::quantization::{QuantizedSubband, StepSize};
+    use crate::subband::Orientation;
+    use crate::tier1::BlockCoder;
+
+    // Bytes of the headers counted with the passes
+    const OVERHEAD: usize = 100;
+
+    /*
+        Two coded subbands of pseudo-random indices (from a linear congruential generator), split in
+        code-blocks of 16x16 samples, with the weights of their distortions.
+    */
+    fn components() -> (Vec<Vec<CodedSubband>>, Vec<Vec<f64>>) {
+        let mut block_coder = BlockCoder::new();
+        block_coder.set_block_size(16, 16);
+        let mut state = 1_u32;
+        let subbands = [(Orientation::LL, 24, 20), (Orientation::HL, 48, 40)].iter().map(|&(orientation, width, height)| {
+            let coefficients = DMatrix::from_fn(height, width, |_, _| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let magnitude = ((state >> 8) % 1024) >> ((state >> 20) % 10);
+                if state & 0x10 != 0 { -(magnitude as i32) } else { magnitude as i32 }
+            });
+            block_coder.encode_subband(&QuantizedSubband::new(orientation, 1, (0, 0), StepSize::new(10, 0), 10, coefficients))
+        }).collect();
+        (vec![subbands], vec![vec![4., 1.]])
+    }
+
+    /*
+        Size of the passes of the last layer, with some overhead.
+    */
+    fn size(components: &[Vec<CodedSubband>], layer_count: usize) -> usize {
+        OVERHEAD + components.iter().flatten().flat_map(|subband| subband.get_blocks()).map(|block| {
+            block.get_length(block.get_layer_pass_count(layer_count - 1))
+        }).sum::<usize>()
+    }
+
+    #[test]
+    fn size_limit_budget() {
+        assert_eq!(SizeLimit::Bytes(1000).get_budget(64), 1000);
+        assert_eq!(SizeLimit::BitsPerPixel(1.5).get_budget(100), 18);
+    }
+
+    #[test]
+    fn limit_within_one_truncation_point() {
+        let (mut components, weights) = components();
+        let points = TruncationPoints::new(&components, &weights);
+        let candidates = points.get_thresholds();
+        let full = size(&components, 1);
+
+        for budget in [OVERHEAD, OVERHEAD + 1, full / 4, full / 2, full - 1, full] {
+            let layers = limit_layers(&mut components, &points, &[0.], budget, size);
+            assert!(size(&components, 1) <= budget);
+
+            // One more truncation point goes over the budget
+            if let Some(&next) = candidates.iter().find(|&&threshold| threshold < layers[0]) {
+                set_layers(&mut components, &points, &[next]);
+                assert!(size(&components, 1) > budget);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "A budget of 99 bytes is too small even without any coding pass.")]
+    fn budget_too_small() {
+        let (mut components, weights) = components();
+        let points = TruncationPoints::new(&components, &weights);
+        limit_layers(&mut components, &points, &[0.], OVERHEAD - 1, size);
+    }
+
+    #[test]
+    fn layers_are_monotonic() {
+        let (mut components, weights) = components();
+        let points = TruncationPoints::new(&components, &weights);
+        let pixel_count = 48 * 40;
+        let targets = [
+            vec![LayerTarget::BitsPerPixel(0.5), LayerTarget::BitsPerPixel(1.), LayerTarget::BitsPerPixel(2.), LayerTarget::BitsPerPixel(8.)],
+            vec![LayerTarget::Psnr(20.), LayerTarget::Psnr(30.), LayerTarget::Psnr(40.)],
+        ];
+
+        for targets in targets {
+            let thresholds = allocate_layers(&mut components, &points, &targets, pixel_count, pixel_count, 255., size);
+            assert!(thresholds.windows(2).all(|pair| pair[0] >= pair[1]));
+            assert!(size(&components, 1) < size(&components, targets.len()));
+
+            let blocks = components.iter().flatten().flat_map(|subband| subband.get_blocks());
+            for block in blocks {
+                let counts: Vec<usize> = (0..targets.len()).map(|layer| block.get_layer_pass_count(layer)).collect();
+                assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+            }
+            for (layer, target) in targets.iter().enumerate() {
+                if let LayerTarget::BitsPerPixel(rate) = *target {
+                    assert!(size(&components, layer + 1) <= (rate * pixel_count as f64 / 8.) as usize);
+                }
+            }
+        }
+    }
+}
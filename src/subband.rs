@@ -0,0 +1,353 @@
+use std::marker::PhantomData;
+use nalgebra::DMatrix;
+use crate::wavelet::{Wavelet, forward_2d, inverse_2d, lowpass_length};
+
+/*
+    Crate of the data produced by the wavelet transform of one channel of an image,
+    as used by the JPEG 2000 quantization and entropy coding.
+*/
+
+/*
+    Orientation of a subband, where the first letter is the filter applied on the rows
+    and the second one the filter applied on the columns (L for lowpass, H for highpass).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    LL,
+    HL,
+    LH,
+    HH,
+}
+
+impl Orientation {
+    /*
+        Get whether the rows (x) and the columns (y) of the subband were highpass filtered (1) or not (0).
+    */
+    pub fn get_x(&self) -> u32 {
+        match self {
+            Orientation::LL | Orientation::LH => 0,
+            Orientation::HL | Orientation::HH => 1,
+        }
+    }
+    pub fn get_y(&self) -> u32 {
+        match self {
+            Orientation::LL | Orientation::HL => 0,
+            Orientation::LH | Orientation::HH => 1,
+        }
+    }
+}
+
+/*
+    Coefficients of one subband, with:
+    - its decomposition level (1 for the finest details, the level count for the LL subband),
+    - its origin, the position of its first coefficient in the coordinates of the subband
+      (as defined by the JPEG 2000 spec, it decides where the code-blocks and precincts start).
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subband {
+    orientation: Orientation,
+    level: usize,
+    origin: (u32, u32),
+    coefficients: DMatrix<f32>,
+}
+
+impl Subband {
+    pub fn new(orientation: Orientation, level: usize, origin: (u32, u32), coefficients: DMatrix<f32>) -> Self {
+        Subband {orientation, level, origin, coefficients}
+    }
+
+    pub fn get_orientation(&self) -> Orientation { self.orientation }
+    pub fn get_level(&self) -> usize { self.level }
+    pub fn get_origin(&self) -> (u32, u32) { self.origin }
+    pub fn get_width(&self) -> u32 { self.coefficients.ncols() as u32 }
+    pub fn get_height(&self) -> u32 { self.coefficients.nrows() as u32 }
+
+    /*
+        Get and set the coefficients (each row of the matrix is a line of the subband).
+    */
+    pub fn get_coefficients(&self) -> &DMatrix<f32> { &self.coefficients }
+    pub fn set_coefficients(&mut self, coefficients: DMatrix<f32>) {
+        if coefficients.shape() != self.coefficients.shape() {
+            panic!("Wrong size of coefficients for the subband.");
+        }
+        self.coefficients = coefficients;
+    }
+}
+
+/*
+    Resolution level of a decomposition: the subbands needed to go from the previous resolution
+    to this one (HL, LH and HH), or the LL subband alone for the resolution 0.
+    Its origin and size are the ones of the image rebuilt at this resolution.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolutionLevel {
+    origin: (u32, u32),
+    width: u32,
+    height: u32,
+    subbands: Vec<Subband>,
+}
+
+impl ResolutionLevel {
+    pub fn new(origin: (u32, u32), width: u32, height: u32, subbands: Vec<Subband>) -> Self {
+        ResolutionLevel {origin, width, height, subbands}
+    }
+
+    pub fn get_origin(&self) -> (u32, u32) { self.origin }
+    pub fn get_width(&self) -> u32 { self.width }
+    pub fn get_height(&self) -> u32 { self.height }
+    pub fn get_subbands(&self) -> &Vec<Subband> { &self.subbands }
+    pub fn get_subbands_mut(&mut self) -> &mut Vec<Subband> { &mut self.subbands }
+}
+
+/*
+    Multi-level decomposition of one channel with the wavelet W: the LL subband is recursively
+    transformed, and the subbands are stored by resolution level, from the coarsest (resolution 0,
+    the last LL subband) to the finest (the details of the first decomposition level).
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decomposition<W> where W: Wavelet {
+    width: u32,
+    height: u32,
+    resolutions: Vec<ResolutionLevel>,
+    _phantom: PhantomData<W>,
+}
+
+impl<W> Decomposition<W> where W: Wavelet {
+    /*
+        Decompose a channel (each row of the matrix is a line) on the given number of levels
+        (JPEG 2000 typically uses 5). The origin is the position of the top left sample of the
+        channel in the image (or the JPEG 2000 reference grid): it decides whether the first line
+        and column are lowpass or highpass samples.
+    */
+    pub fn new(channel: &DMatrix<f32>, origin: (u32, u32), level_count: usize) -> Self {
+        Self::split(channel, origin, level_count, |ll, origin| forward_2d::<W>(ll, origin))
+    }
+
+    /*
+        Create the decomposition of a channel of the given size with all its coefficients at 0,
+        without any transform: the decoder only needs the geometry of its subbands.
+    */
+    pub fn zeros(origin: (u32, u32), width: u32, height: u32, level_count: usize) -> Self {
+        // The transform of zeros is zeros
+        Self::split(&DMatrix::zeros(height as usize, width as usize), origin, level_count, |ll, _| ll.clone())
+    }
+
+    /*
+        Split a channel in subbands on the given number of levels, each LL subband being
+        transformed at its origin by transform (the 2D transform of the wavelet).
+    */
+    fn split(channel: &DMatrix<f32>, origin: (u32, u32), level_count: usize, transform: impl Fn(&DMatrix<f32>, (usize, usize)) -> DMatrix<f32>) -> Self {
+        let mut ll = channel.clone();
+        let mut ll_origin = origin;
+        let mut resolutions: Vec<ResolutionLevel> = Vec::new();
+
+        for level in 1..=level_count {
+            let (x0, y0) = ll_origin;
+            let (height, width) = ll.shape();
+            let low_width = lowpass_length(width, x0 as usize);
+            let low_height = lowpass_length(height, y0 as usize);
+            let (high_width, high_height) = (width - low_width, height - low_height);
+            let transformed = transform(&ll, (x0 as usize, y0 as usize));
+
+            // The lowpass samples are the even ones, the highpass samples the odd ones
+            let low_origin = (x0.div_ceil(2), y0.div_ceil(2));
+            let high_origin = (x0 / 2, y0 / 2);
+
+            let hl = transformed.slice((0, low_width), (low_height, high_width)).into_owned();
+            let lh = transformed.slice((low_height, 0), (high_height, low_width)).into_owned();
+            let hh = transformed.slice((low_height, low_width), (high_height, high_width)).into_owned();
+            let subbands = vec![
+                Subband::new(Orientation::HL, level, (high_origin.0, low_origin.1), hl),
+                Subband::new(Orientation::LH, level, (low_origin.0, high_origin.1), lh),
+                Subband::new(Orientation::HH, level, high_origin, hh),
+            ];
+            resolutions.push(ResolutionLevel::new(ll_origin, width as u32, height as u32, subbands));
+
+            ll = transformed.slice((0, 0), (low_height, low_width)).into_owned();
+            ll_origin = low_origin;
+        }
+
+        let (height, width) = ll.shape();
+        let ll_subband = Subband::new(Orientation::LL, level_count, ll_origin, ll);
+        resolutions.push(ResolutionLevel::new(ll_origin, width as u32, height as u32, vec![ll_subband]));
+        resolutions.reverse();
+
+        Decomposition {
+            width: channel.ncols() as u32,
+            height: channel.nrows() as u32,
+            resolutions,
+            _phantom: PhantomData,
+        }
+    }
+
+    /*
+        Rebuild the channel, from the coarsest resolution to the finest one.
+    */
+    pub fn reconstruct(&self) -> DMatrix<f32> {
+        self.reconstruct_resolution(self.get_level_count())
+    }
+
+    /*
+        Rebuild the channel at a lower resolution (0 gives the LL subband alone).
+    */
+    pub fn reconstruct_resolution(&self, resolution: usize) -> DMatrix<f32> {
+        let mut ll = self.resolutions[0].get_subbands()[0].get_coefficients().clone();
+
+        for level in &self.resolutions[1..=resolution] {
+            let (x0, y0) = level.get_origin();
+            let mut transformed = DMatrix::zeros(level.get_height() as usize, level.get_width() as usize);
+            let (low_height, low_width) = ll.shape();
+
+            transformed.slice_mut((0, 0), ll.shape()).copy_from(&ll);
+            for subband in level.get_subbands() {
+                let row = (subband.get_orientation().get_y() as usize) * low_height;
+                let col = (subband.get_orientation().get_x() as usize) * low_width;
+                transformed.slice_mut((row, col), subband.get_coefficients().shape()).copy_from(subband.get_coefficients());
+            }
+
+            ll = inverse_2d::<W>(&transformed, (x0 as usize, y0 as usize));
+        }
+
+        ll
+    }
+
+    /*
+        Get all the subbands in the usual Mallat layout (for inspection):
+
+             LL2 | HL2 |
+            -----+-----| HL1
+             LH2 | HH2 |
+            -----------+-----
+                 |
+                LH1    | HH1
+                 |
+    */
+    pub fn to_matrix(&self) -> DMatrix<f32> {
+        let mut matrix = DMatrix::zeros(self.height as usize, self.width as usize);
+        let ll = self.resolutions[0].get_subbands()[0].get_coefficients();
+        matrix.slice_mut((0, 0), ll.shape()).copy_from(ll);
+
+        for level in &self.resolutions[1..] {
+            let low_width = lowpass_length(level.get_width() as usize, level.get_origin().0 as usize);
+            let low_height = lowpass_length(level.get_height() as usize, level.get_origin().1 as usize);
+            for subband in level.get_subbands() {
+                let row = (subband.get_orientation().get_y() as usize) * low_height;
+                let col = (subband.get_orientation().get_x() as usize) * low_width;
+                matrix.slice_mut((row, col), subband.get_coefficients().shape()).copy_from(subband.get_coefficients());
+            }
+        }
+
+        matrix
+    }
+
+    pub fn get_level_count(&self) -> usize { self.resolutions.len() - 1 }
+
+    /*
+        Iterate over the subbands in resolution order: LL, then HL, LH and HH of each level
+        from the coarsest to the finest.
+    */
+    pub fn subbands(&self) -> impl Iterator<Item = &Subband> {
+        self.resolutions.iter().flat_map(|level| level.get_subbands().iter())
+    }
+
+    pub fn subbands_mut(&mut self) -> impl Iterator<Item = &mut Subband> {
+        self.resolutions.iter_mut().flat_map(|level| level.get_subbands_mut().iter_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wavelet::{LeGall53, Cdf97};
+
+    const LEVEL_COUNT: usize = 3;
+    // Sizes (height, width) and origins (x0, y0) of the channels decomposed, with odd and even values
+    const SIZES: [(usize, usize); 4] = [(16, 16), (13, 11), (9, 20), (5, 3)];
+    const ORIGINS: [(u32, u32); 5] = [(0, 0), (1, 0), (0, 1), (3, 5), (7, 2)];
+
+    fn channel(height: usize, width: usize, seed: u32) -> DMatrix<f32> {
+        let mut state = seed;
+        DMatrix::from_fn(height, width, |_, _| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as f32 - 128.
+        })
+    }
+
+    /*
+        Ceiling of a / 2^shift, for a possibly negative a.
+    */
+    fn ceil_shift(a: i64, shift: usize) -> i64 {
+        -((-a) >> shift)
+    }
+
+    /*
+        Bounds [start, end) of a subband of the given level along one axis, from the bounds [x0, x1)
+        of the channel and whether the subband was highpass filtered on this axis (B-15 in the spec).
+    */
+    fn subband_bounds(x0: u32, x1: u32, level: usize, high: u32) -> (u32, u32) {
+        let shift = (1_i64 << (level - 1)) * high as i64;
+        let start = ceil_shift(x0 as i64 - shift, level);
+        let end = ceil_shift(x1 as i64 - shift, level);
+        (start as u32, end as u32)
+    }
+
+    #[test]
+    fn subband_geometry() {
+        for (seed, &(height, width)) in SIZES.iter().enumerate() {
+            for &(x0, y0) in &ORIGINS {
+                let (x1, y1) = (x0 + width as u32, y0 + height as u32);
+                let decomposition = Decomposition::<Cdf97>::new(&channel(height, width, seed as u32), (x0, y0), LEVEL_COUNT);
+                let zeros = Decomposition::<Cdf97>::zeros((x0, y0), width as u32, height as u32, LEVEL_COUNT);
+
+                for (subband, zero) in decomposition.subbands().zip(zeros.subbands()) {
+                    let orientation = subband.get_orientation();
+                    let level = subband.get_level();
+                    let (start_x, end_x) = subband_bounds(x0, x1, level, orientation.get_x());
+                    let (start_y, end_y) = subband_bounds(y0, y1, level, orientation.get_y());
+                    let name = format!("{:?}{} of {}x{} at {:?}", orientation, level, width, height, (x0, y0));
+
+                    assert_eq!(subband.get_origin(), (start_x, start_y), "{}", name);
+                    assert_eq!((subband.get_width(), subband.get_height()), (end_x - start_x, end_y - start_y), "{}", name);
+                    assert_eq!((zero.get_origin(), zero.get_width(), zero.get_height()), (subband.get_origin(), subband.get_width(), subband.get_height()), "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn subbands_in_resolution_order() {
+        let mut expected = vec![(Orientation::LL, LEVEL_COUNT)];
+        for level in (1..=LEVEL_COUNT).rev() {
+            expected.extend([Orientation::HL, Orientation::LH, Orientation::HH].iter().map(|&orientation| (orientation, level)));
+        }
+
+        let mut decomposition = Decomposition::<LeGall53>::new(&channel(13, 11, 1), (3, 5), LEVEL_COUNT);
+        let order: Vec<(Orientation, usize)> = decomposition.subbands().map(|subband| (subband.get_orientation(), subband.get_level())).collect();
+        assert_eq!(order, expected);
+        let order: Vec<(Orientation, usize)> = decomposition.subbands_mut().map(|subband| (subband.get_orientation(), subband.get_level())).collect();
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn reconstructed_resolutions() {
+        for (seed, &(height, width)) in SIZES.iter().enumerate() {
+            for &(x0, y0) in &ORIGINS {
+                let (x1, y1) = (x0 + width as u32, y0 + height as u32);
+                let samples = channel(height, width, seed as u32);
+                let decomposition = Decomposition::<LeGall53>::new(&samples, (x0, y0), LEVEL_COUNT);
+
+                for resolution in 0..=LEVEL_COUNT {
+                    let shift = LEVEL_COUNT - resolution;
+                    let expected_width = ceil_shift(x1 as i64, shift) - ceil_shift(x0 as i64, shift);
+                    let expected_height = ceil_shift(y1 as i64, shift) - ceil_shift(y0 as i64, shift);
+                    let rebuilt = decomposition.reconstruct_resolution(resolution);
+                    assert_eq!(rebuilt.shape(), (expected_height as usize, expected_width as usize), "resolution {} of {}x{} at {:?}", resolution, width, height, (x0, y0));
+                }
+
+                let ll = decomposition.subbands().next().unwrap().get_coefficients();
+                assert_eq!(&decomposition.reconstruct_resolution(0), ll);
+                assert_eq!(decomposition.reconstruct(), samples, "{}x{} at {:?}", width, height, (x0, y0));
+            }
+        }
+    }
+}
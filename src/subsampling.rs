@@ -0,0 +1,258 @@
+use nalgebra::DMatrix;
+
+/*
+    Crate of the chroma subsampling of the components: the chroma components (Cb and Cr) are
+    downsampled by a factor in each direction, and upsampled back to the size of the image.
+
+    Each sample of a downsampled component is sited either:
+    - centered between the samples it replaces (as in JPEG and MPEG-1),
+    - co-sited with the first of them (as in MPEG-2 and JPEG 2000, where the sample k of a component
+      with the subsampling factor dx lies at k * dx on the reference grid).
+*/
+
+/*
+    Usual chroma subsampling schemes, from the sampling factors of the luminance and chroma
+    in a line of 4 pixels and the next one.
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    Yuv444,
+    Yuv422,
+    Yuv420,
+    Yuv411,
+}
+
+impl ChromaSubsampling {
+    /*
+        Get the horizontal and vertical subsampling factors of the chroma components.
+    */
+    pub fn get_factors(&self) -> (usize, usize) {
+        match self {
+            ChromaSubsampling::Yuv444 => (1, 1),
+            ChromaSubsampling::Yuv422 => (2, 1),
+            ChromaSubsampling::Yuv420 => (2, 2),
+            ChromaSubsampling::Yuv411 => (4, 1),
+        }
+    }
+}
+
+/*
+    Siting of the downsampled samples.
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSiting {
+    Centered,
+    CoSited,
+}
+
+impl ChromaSiting {
+    /*
+        Get the position of the first downsampled sample on the full resolution grid.
+    */
+    fn get_offset(&self, factor: usize) -> f32 {
+        match self {
+            ChromaSiting::Centered => (factor as f32 - 1.) / 2.,
+            ChromaSiting::CoSited => 0.,
+        }
+    }
+}
+
+/*
+    Filters of the downsampling:
+    - box: the mean of the samples replaced,
+    - low-pass: a triangle filter twice as wide as the factor, centered on the site of the sample
+      (it lets less aliasing through than the box).
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownsamplingFilter {
+    Box,
+    LowPass,
+}
+
+/*
+    Filters of the upsampling: the nearest downsampled sample, or the bilinear interpolation
+    of the two nearest ones in each direction.
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsamplingFilter {
+    Nearest,
+    Bilinear,
+}
+
+// Input samples and their weights for each output sample along one direction
+type Weights = Vec<Vec<(usize, f32)>>;
+
+/*
+    Get the weights of the downsampling of a line of the given length.
+*/
+fn downsampling_weights(length: usize, factor: usize, filter: DownsamplingFilter, siting: ChromaSiting) -> Weights {
+    (0..length.div_ceil(factor)).map(|k| {
+        let weights: Vec<(usize, f32)> = match filter {
+            DownsamplingFilter::Box => (k * factor..((k + 1) * factor).min(length)).map(|i| (i, 1.)).collect(),
+            DownsamplingFilter::LowPass => {
+                let center = (k * factor) as f32 + siting.get_offset(factor);
+                // Only the samples closer than the factor to the center have a weight
+                let first = (center - factor as f32).floor().max(0.) as usize;
+                let last = ((center + factor as f32).ceil() as usize).min(length - 1);
+                (first..=last).filter_map(|i| {
+                    let weight = 1. - (i as f32 - center).abs() / factor as f32;
+                    (weight > 0.).then_some((i, weight))
+                }).collect()
+            },
+        };
+
+        // The weights are normalized, which also handles the borders
+        let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+        weights.into_iter().map(|(i, weight)| (i, weight / total)).collect()
+    }).collect()
+}
+
+/*
+    Get the weights of the upsampling of a downsampled line (of the input length) to a line of
+    the given length.
+*/
+fn upsampling_weights(length: usize, input_length: usize, factor: usize, filter: UpsamplingFilter, siting: ChromaSiting) -> Weights {
+    let last = input_length - 1;
+
+    (0..length).map(|x| {
+        let position = ((x as f32 - siting.get_offset(factor)) / factor as f32).clamp(0., last as f32);
+        match filter {
+            UpsamplingFilter::Nearest => vec![(position.round() as usize, 1.)],
+            UpsamplingFilter::Bilinear => {
+                let first = position.floor() as usize;
+                let t = position - first as f32;
+                vec![(first, 1. - t), ((first + 1).min(last), t)]
+            },
+        }
+    }).collect()
+}
+
+/*
+    Apply weights to the lines, then to the columns of a component.
+*/
+fn resample(component: &DMatrix<f32>, horizontal: &Weights, vertical: &Weights) -> DMatrix<f32> {
+    let lines = DMatrix::from_fn(component.nrows(), horizontal.len(), |y, x| {
+        horizontal[x].iter().map(|&(i, weight)| component[(y, i)] * weight).sum::<f32>()
+    });
+    DMatrix::from_fn(vertical.len(), horizontal.len(), |y, x| {
+        vertical[y].iter().map(|&(i, weight)| lines[(i, x)] * weight).sum::<f32>()
+    })
+}
+
+/*
+    Downsample a component (each row of the matrix is a line) by dx horizontally and dy vertically.
+    The downsampled component has ceil(width / dx) columns and ceil(height / dy) rows.
+*/
+pub fn downsample(component: &DMatrix<f32>, dx: usize, dy: usize, filter: DownsamplingFilter, siting: ChromaSiting) -> DMatrix<f32> {
+    if dx == 0 || dy == 0 {
+        panic!("The subsampling factors must be positive.");
+    }
+    let (height, width) = component.shape();
+
+    resample(
+        component,
+        &downsampling_weights(width, dx, filter, siting),
+        &downsampling_weights(height, dy, filter, siting),
+    )
+}
+
+/*
+    Upsample a component downsampled by dx horizontally and dy vertically back to the given size.
+*/
+pub fn upsample(component: &DMatrix<f32>, width: usize, height: usize, dx: usize, dy: usize, filter: UpsamplingFilter, siting: ChromaSiting) -> DMatrix<f32> {
+    if dx == 0 || dy == 0 {
+        panic!("The subsampling factors must be positive.");
+    }
+    if component.ncols() != width.div_ceil(dx) || component.nrows() != height.div_ceil(dy) {
+        panic!("A {}x{} component can not be upsampled to {}x{} by {}x{}.", component.ncols(), component.nrows(), width, height, dx, dy);
+    }
+
+    resample(
+        component,
+        &upsampling_weights(width, component.ncols(), dx, filter, siting),
+        &upsampling_weights(height, component.nrows(), dy, filter, siting),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMES: [ChromaSubsampling; 4] = [ChromaSubsampling::Yuv444, ChromaSubsampling::Yuv422, ChromaSubsampling::Yuv420, ChromaSubsampling::Yuv411];
+    const SITINGS: [ChromaSiting; 2] = [ChromaSiting::Centered, ChromaSiting::CoSited];
+
+    /*
+        Component with pseudo-random samples (from a linear congruential generator).
+    */
+    fn component(width: usize, height: usize) -> DMatrix<f32> {
+        let mut state = 5_u32;
+        DMatrix::from_fn(height, width, |_, _| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as f32
+        })
+    }
+
+    #[test]
+    fn output_sizes() {
+        for scheme in SCHEMES {
+            let (dx, dy) = scheme.get_factors();
+            for (width, height) in [(1, 1), (7, 5), (13, 9), (16, 8)] {
+                let downsampled = downsample(&component(width, height), dx, dy, DownsamplingFilter::LowPass, ChromaSiting::CoSited);
+                assert_eq!(downsampled.shape(), (height.div_ceil(dy), width.div_ceil(dx)));
+                let upsampled = upsample(&downsampled, width, height, dx, dy, UpsamplingFilter::Bilinear, ChromaSiting::CoSited);
+                assert_eq!(upsampled.shape(), (height, width));
+            }
+        }
+    }
+
+    #[test]
+    fn constant_plane() {
+        let plane = DMatrix::from_element(9, 13, 37.5);
+        for filter in [DownsamplingFilter::Box, DownsamplingFilter::LowPass] {
+            for siting in SITINGS {
+                for scheme in SCHEMES {
+                    let (dx, dy) = scheme.get_factors();
+                    let downsampled = downsample(&plane, dx, dy, filter, siting);
+                    assert!(downsampled.iter().all(|&sample| (sample - 37.5).abs() < 1e-4), "{:?} {:?} {:?}", filter, siting, scheme);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_upsampling_keeps_the_samples() {
+        let downsampled = component(4, 3);
+        for scheme in SCHEMES {
+            let (dx, dy) = scheme.get_factors();
+            let (width, height) = (3 * dx + 1, 2 * dy + 1);
+            let upsampled = upsample(&downsampled, width, height, dx, dy, UpsamplingFilter::Nearest, ChromaSiting::CoSited);
+            for (y, x) in (0..3).flat_map(|y| (0..4).map(move |x| (y, x))) {
+                assert_eq!(upsampled[(y * dy, x * dx)], downsampled[(y, x)]);
+            }
+        }
+    }
+
+    #[test]
+    fn low_pass_weights() {
+        // The weights of the triangle filter over the whole line
+        for siting in SITINGS {
+            for (length, factor) in [(1, 2), (7, 2), (13, 4), (9, 3)] {
+                let weights = downsampling_weights(length, factor, DownsamplingFilter::LowPass, siting);
+                for (k, weights) in weights.iter().enumerate() {
+                    let center = (k * factor) as f32 + siting.get_offset(factor);
+                    let expected: Vec<(usize, f32)> = (0..length).filter_map(|i| {
+                        let weight = 1. - (i as f32 - center).abs() / factor as f32;
+                        (weight > 0.).then_some((i, weight))
+                    }).collect();
+                    let total: f32 = expected.iter().map(|(_, weight)| weight).sum();
+                    let expected: Vec<(usize, f32)> = expected.into_iter().map(|(i, weight)| (i, weight / total)).collect();
+                    assert_eq!(*weights, expected);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::tier2::{BitReader, BitWriter};
+
+/*
+    Crate of the tag trees used by the packet headers (B.10.2 of the spec).
+
+    A tag tree codes a 2D array of non-negative values (one per code-block of a precinct) with a
+    quad-tree: each node holds the minimum of its (up to 4) children, and the root the minimum of
+    the whole array. A value is coded against a threshold from the root down to the leaf, each node
+    only coding the part of its value above its parent which was not already coded, so the
+    information shared by the code-blocks is only sent once.
+*/
+
+// Value of the nodes which are not known yet
+const UNKNOWN: u32 = u32::MAX;
+
+/*
+    Node of a tag tree: its value, the lower bound of the value already coded and whether the value
+    itself was coded.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Node {
+    value: u32,
+    low: u32,
+    known: bool,
+}
+
+/*
+    Tag tree over an array of the given size. The levels are stored from the leaves (the array) to
+    the root, each one row by row.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagTree {
+    width: usize,
+    height: usize,
+    levels: Vec<(usize, usize)>,
+    nodes: Vec<Node>,
+}
+
+impl TagTree {
+    /*
+        Create a tag tree whose values are all unknown (for decoding, or to be set for encoding).
+    */
+    pub fn new(width: usize, height: usize) -> Self {
+        let mut levels = vec![(width, height)];
+        let (mut w, mut h) = (width, height);
+        while w * h > 1 {
+            w = w.div_ceil(2);
+            h = h.div_ceil(2);
+            levels.push((w, h));
+        }
+
+        let count = levels.iter().map(|(w, h)| w * h).sum();
+        TagTree {width, height, levels, nodes: vec![Node {value: UNKNOWN, low: 0, known: false}; count]}
+    }
+
+    /*
+        Get the indices of the nodes from a leaf to the root.
+    */
+    fn path(&self, x: usize, y: usize) -> Vec<usize> {
+        let mut path = Vec::with_capacity(self.levels.len());
+        let (mut x, mut y, mut start) = (x, y, 0);
+
+        for &(w, h) in &self.levels {
+            if w * h == 0 {
+                break;
+            }
+            path.push(start + y * w + x);
+            start += w * h;
+            x /= 2;
+            y /= 2;
+        }
+
+        path
+    }
+
+    /*
+        Set the value of a leaf, and update the minimums of its parents.
+    */
+    pub fn set_value(&mut self, x: usize, y: usize, value: u32) {
+        for node in self.path(x, y) {
+            if self.nodes[node].value <= value {
+                break;
+            }
+            self.nodes[node].value = value;
+        }
+    }
+
+    /*
+        Code the value of a leaf, as far as needed to tell whether it is below the threshold:
+        for each node from the root, a 0 bit for each increment of its lower bound and a 1 bit
+        when the value is reached.
+    */
+    pub fn encode(&mut self, writer: &mut BitWriter, x: usize, y: usize, threshold: u32) {
+        let mut low = 0;
+
+        for index in self.path(x, y).into_iter().rev() {
+            let node = &mut self.nodes[index];
+            low = low.max(node.low);
+
+            while low < threshold {
+                if low >= node.value {
+                    if !node.known {
+                        writer.write_bit(1);
+                        node.known = true;
+                    }
+                    break;
+                }
+                writer.write_bit(0);
+                low += 1;
+            }
+            node.low = low;
+        }
+    }
+
+    /*
+        Decode the value of a leaf as far as needed to tell whether it is below the threshold,
+        and get whether it is.
+    */
+    pub fn decode(&mut self, reader: &mut BitReader, x: usize, y: usize, threshold: u32) -> bool {
+        let path = self.path(x, y);
+        let mut low = 0;
+
+        for &index in path.iter().rev() {
+            let node = &mut self.nodes[index];
+            low = low.max(node.low);
+
+            while low < threshold && low < node.value {
+                if reader.read_bit() == 1 {
+                    node.value = low;
+                } else {
+                    low += 1;
+                }
+            }
+            node.low = low;
+        }
+
+        self.nodes[path[0]].value < threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values of a 5x3 array of leaves, with gaps and repeated minimums
+    const VALUES: [[u32; 5]; 3] = [
+        [3, 1, 4, 1, 5],
+        [9, 2, 6, 5, 3],
+        [0, 8, 7, 9, 3],
+    ];
+
+    #[test]
+    fn tag_tree_round_trip() {
+        let mut encoder = TagTree::new(5, 3);
+        for (y, row) in VALUES.iter().enumerate() {
+            for (x, &value) in row.iter().enumerate() {
+                encoder.set_value(x, y, value);
+            }
+        }
+
+        // Each leaf against increasing thresholds, as the inclusion of the code-blocks layer after layer
+        let mut writer = BitWriter::new();
+        for threshold in 1..=10 {
+            for (x, y) in (0..3).flat_map(|y| (0..5).map(move |x| (x, y))) {
+                encoder.encode(&mut writer, x, y, threshold);
+            }
+        }
+        let bytes = writer.finish();
+
+        let mut decoder = TagTree::new(5, 3);
+        let mut reader = BitReader::new(&bytes);
+        for threshold in 1..=10 {
+            for (x, y) in (0..3).flat_map(|y| (0..5).map(move |x| (x, y))) {
+                assert_eq!(decoder.decode(&mut reader, x, y, threshold), VALUES[y][x] < threshold);
+            }
+        }
+    }
+
+    #[test]
+    fn tag_tree_values() {
+        // The values found with the lowest threshold above each one, as the zero bit-planes
+        let mut encoder = TagTree::new(5, 3);
+        let mut writer = BitWriter::new();
+        for (x, y) in (0..3).flat_map(|y| (0..5).map(move |x| (x, y))) {
+            encoder.set_value(x, y, VALUES[y][x]);
+        }
+        for (x, y) in (0..3).flat_map(|y| (0..5).map(move |x| (x, y))).rev() {
+            encoder.encode(&mut writer, x, y, VALUES[y][x] + 1);
+        }
+        let bytes = writer.finish();
+
+        let mut decoder = TagTree::new(5, 3);
+        let mut reader = BitReader::new(&bytes);
+        for (x, y) in (0..3).flat_map(|y| (0..5).map(move |x| (x, y))).rev() {
+            let mut threshold = 1;
+            while !decoder.decode(&mut reader, x, y, threshold) {
+                threshold += 1;
+            }
+            assert_eq!(threshold - 1, VALUES[y][x]);
+        }
+    }
+}
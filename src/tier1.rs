@@ -0,0 +1,930 @@
+use nalgebra::DMatrix;
+use crate::mq::{MqDecoder, MqEncoder, RUN_LENGTH_CONTEXT, UNIFORM_CONTEXT};
+use crate::quantization::{QuantizedSubband, StepSize};
+use crate::subband::{Orientation, Subband};
+
+/*
+    Crate of the EBCOT tier-1 coding: the bit-plane coding of the code-blocks of the quantized
+    subbands with the MQ coder (Annex D of the spec).
+
+    The quantization indices of a code-block are coded in sign-magnitude form, from the most
+    significant non-zero bit-plane down to the least significant one. The first bit-plane is coded
+    by a single cleanup pass, and each following one by three passes:
+    - significance propagation: the samples which are not significant yet but have a significant
+      neighbour (they are the most likely to become significant),
+    - magnitude refinement: the samples which became significant in a previous bit-plane,
+    - cleanup: the remaining samples, with a run-length mode for the columns of insignificant samples.
+    The samples are scanned by stripes of 4 lines, column by column in each stripe.
+
+    Each pass is a possible truncation point of the code-block, for which the encoder gives the
+    number of bytes needed and the decrease of distortion, as needed by the rate control.
+*/
+
+// Flags of a sample during the coding
+const SIGNIFICANT: u8 = 1;
+const VISITED: u8 = 2;
+const REFINED: u8 = 4;
+const NEGATIVE: u8 = 8;
+
+/*
+    Options of the code-block coding, as signalled by the code-block style of the COD marker segment:
+    - reset: the contexts are reset at the end of each pass,
+    - terminate all: the codeword is terminated at the end of each pass,
+    - vertically causal: the context of a sample does not depend on the next stripe,
+    - predictable termination: the codewords are terminated with the predictable termination,
+    - segmentation symbols: the symbols 1010 are coded at the end of each cleanup pass.
+    The selective arithmetic coding bypass (raw coding of the last bit-planes) is not supported.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CodeBlockStyle {
+    reset: bool,
+    terminate_all: bool,
+    vertically_causal: bool,
+    predictable_termination: bool,
+    segmentation_symbols: bool,
+}
+
+impl CodeBlockStyle {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn get_terminate_all(&self) -> bool { self.terminate_all }
+
+    #[allow(dead_code)]
+    pub fn set_reset(&mut self, reset: bool) { self.reset = reset; }
+    #[allow(dead_code)]
+    pub fn set_terminate_all(&mut self, terminate_all: bool) { self.terminate_all = terminate_all; }
+    #[allow(dead_code)]
+    pub fn set_vertically_causal(&mut self, vertically_causal: bool) { self.vertically_causal = vertically_causal; }
+    #[allow(dead_code)]
+    pub fn set_predictable_termination(&mut self, predictable_termination: bool) { self.predictable_termination = predictable_termination; }
+    #[allow(dead_code)]
+    pub fn set_segmentation_symbols(&mut self, segmentation_symbols: bool) { self.segmentation_symbols = segmentation_symbols; }
+
+    /*
+        Get the style from and to its code in the marker segments.
+    */
+    pub fn get_code(&self) -> u8 {
+        (self.reset as u8) << 1
+            | (self.terminate_all as u8) << 2
+            | (self.vertically_causal as u8) << 3
+            | (self.predictable_termination as u8) << 4
+            | (self.segmentation_symbols as u8) << 5
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        if code & 1 != 0 {
+            panic!("The selective arithmetic coding bypass is not supported.");
+        }
+        CodeBlockStyle {
+            reset: code & 0x02 != 0,
+            terminate_all: code & 0x04 != 0,
+            vertically_causal: code & 0x08 != 0,
+            predictable_termination: code & 0x10 != 0,
+            segmentation_symbols: code & 0x20 != 0,
+        }
+    }
+}
+
+/*
+    Kind of a coding pass.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassKind {
+    SignificancePropagation,
+    MagnitudeRefinement,
+    Cleanup,
+}
+
+impl PassKind {
+    /*
+        Get the kind of the pass of the given index in a code-block, and the bit-plane it codes,
+        counted from the most significant non-zero bit-plane (0).
+    */
+    pub fn from_index(index: usize) -> (PassKind, usize) {
+        let kind = match index % 3 {
+            0 => PassKind::Cleanup,
+            1 => PassKind::SignificancePropagation,
+            _ => PassKind::MagnitudeRefinement,
+        };
+        (kind, index.div_ceil(3))
+    }
+}
+
+/*
+    Coding pass of a code-block, with:
+    - the number of bytes of the code-block data needed to decode it and all the previous passes,
+    - the decrease of distortion it brings, in squared quantization indices (it must be multiplied by
+      the squares of the step size and the synthesis norm of the subband to be compared between subbands),
+    - whether the codeword is terminated at its end.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodingPass {
+    kind: PassKind,
+    length: usize,
+    distortion: f64,
+    terminated: bool,
+}
+
+impl CodingPass {
+    pub fn new(kind: PassKind, length: usize, distortion: f64, terminated: bool) -> Self {
+        CodingPass {kind, length, distortion, terminated}
+    }
+
+    pub fn get_distortion(&self) -> f64 { self.distortion }
+    pub fn get_terminated(&self) -> bool { self.terminated }
+}
+
+/*
+    Coded code-block: its position and size in the subband, the number of missing most significant
+    bit-planes (the bit-planes of the subband magnitude bits which are all 0), its coding passes and
+    the coded data.
+    The passes are split between the quality layers by giving the number of passes included up to
+    each layer (by default, every pass is in the first layer).
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    zero_bit_planes: u8,
+    passes: Vec<CodingPass>,
+    data: Vec<u8>,
+    layers: Vec<usize>,
+}
+
+impl CodeBlock {
+    pub fn new(x: u32, y: u32, width: u32, height: u32, zero_bit_planes: u8, passes: Vec<CodingPass>, data: Vec<u8>) -> Self {
+        CodeBlock {x, y, width, height, zero_bit_planes, passes, data, layers: Vec::new()}
+    }
+
+    pub fn get_x(&self) -> u32 { self.x }
+    pub fn get_y(&self) -> u32 { self.y }
+    #[allow(dead_code)]
+    pub fn get_width(&self) -> u32 { self.width }
+    #[allow(dead_code)]
+    pub fn get_height(&self) -> u32 { self.height }
+    pub fn get_zero_bit_planes(&self) -> u8 { self.zero_bit_planes }
+    pub fn get_passes(&self) -> &Vec<CodingPass> { &self.passes }
+    pub fn get_data(&self) -> &Vec<u8> { &self.data }
+
+    /*
+        Get the number of bytes needed to decode the first passes of the code-block.
+    */
+    pub fn get_length(&self, pass_count: usize) -> usize {
+        match pass_count {
+            0 => 0,
+            n => self.passes[n - 1].length,
+        }
+    }
+
+    /*
+        Get the number of passes included up to the given quality layer.
+    */
+    pub fn get_layer_pass_count(&self, layer: usize) -> usize {
+        match self.layers.get(layer).or(self.layers.last()) {
+            Some(&count) => count,
+            None => self.passes.len(),
+        }
+    }
+
+    /*
+        Set the number of passes included up to each quality layer (it can only grow).
+    */
+    pub fn set_layers(&mut self, layers: Vec<usize>) {
+        if layers.windows(2).any(|pair| pair[0] > pair[1]) || layers.iter().any(|&count| count > self.passes.len()) {
+            panic!("Invalid number of passes in the layers of a code-block.");
+        }
+        self.layers = layers;
+    }
+
+    pub fn set_zero_bit_planes(&mut self, zero_bit_planes: u8) { self.zero_bit_planes = zero_bit_planes; }
+
+    /*
+        Add passes decoded from a packet with their data.
+    */
+    pub fn add_passes(&mut self, passes: &[CodingPass], data: &[u8]) {
+        self.passes.extend_from_slice(passes);
+        self.data.extend_from_slice(data);
+    }
+}
+
+/*
+    Coded subband: its code-blocks (row by row) and everything needed to get the quantized subband back.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodedSubband {
+    orientation: Orientation,
+    level: usize,
+    origin: (u32, u32),
+    width: u32,
+    height: u32,
+    step_size: StepSize,
+    magnitude_bits: u8,
+    blocks_wide: u32,
+    blocks_high: u32,
+    blocks: Vec<CodeBlock>,
+}
+
+impl CodedSubband {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(orientation: Orientation, level: usize, origin: (u32, u32), width: u32, height: u32, step_size: StepSize, magnitude_bits: u8, blocks_wide: u32, blocks_high: u32, blocks: Vec<CodeBlock>) -> Self {
+        CodedSubband {orientation, level, origin, width, height, step_size, magnitude_bits, blocks_wide, blocks_high, blocks}
+    }
+
+    pub fn get_orientation(&self) -> Orientation { self.orientation }
+    pub fn get_level(&self) -> usize { self.level }
+    pub fn get_origin(&self) -> (u32, u32) { self.origin }
+    pub fn get_width(&self) -> u32 { self.width }
+    pub fn get_height(&self) -> u32 { self.height }
+    pub fn get_step_size(&self) -> StepSize { self.step_size }
+    #[allow(dead_code)]
+    pub fn get_magnitude_bits(&self) -> u8 { self.magnitude_bits }
+    #[allow(dead_code)]
+    pub fn get_blocks_wide(&self) -> u32 { self.blocks_wide }
+    #[allow(dead_code)]
+    pub fn get_blocks_high(&self) -> u32 { self.blocks_high }
+    pub fn get_blocks(&self) -> &Vec<CodeBlock> { &self.blocks }
+    pub fn get_blocks_mut(&mut self) -> &mut Vec<CodeBlock> { &mut self.blocks }
+}
+
+/*
+    Split the interval [origin, origin + length) of the subband coordinates on a grid of the given
+    step (anchored at 0), and get the intervals relative to the origin (start and length).
+*/
+pub fn partition(origin: u32, length: u32, step: u32) -> Vec<(u32, u32)> {
+    if length == 0 {
+        return Vec::new();
+    }
+
+    let end = origin + length;
+    (origin / step..end.div_ceil(step)).map(|k| {
+        let start = (k * step).max(origin);
+        let stop = ((k + 1) * step).min(end);
+        (start - origin, stop - start)
+    }).collect()
+}
+
+/*
+    Binary coder used by the coding passes: the MQ encoder codes the given bit, the MQ decoder ignores
+    it and decodes one, and both return the bit, so that the passes are written once for both.
+*/
+trait BitCoder {
+    fn code(&mut self, bit: u8, context: usize) -> u8;
+}
+
+impl BitCoder for MqEncoder {
+    fn code(&mut self, bit: u8, context: usize) -> u8 {
+        self.encode(bit, context);
+        bit
+    }
+}
+
+impl BitCoder for MqDecoder<'_> {
+    fn code(&mut self, _bit: u8, context: usize) -> u8 {
+        self.decode(context)
+    }
+}
+
+/*
+    Context of the significance coding (Table D.1 of the spec), from the number of significant
+    horizontal (h), vertical (v) and diagonal (d) neighbours.
+*/
+fn zero_coding_context(orientation: Orientation, h: u32, v: u32, d: u32) -> usize {
+    // The HL subbands use the table of the LL and LH subbands with h and v swapped
+    let (h, v) = match orientation {
+        Orientation::HL => (v, h),
+        _ => (h, v),
+    };
+
+    match orientation {
+        Orientation::HH => match (d, h + v) {
+            (d, _) if d >= 3 => 8,
+            (2, hv) if hv >= 1 => 7,
+            (2, _) => 6,
+            (1, hv) if hv >= 2 => 5,
+            (1, 1) => 4,
+            (1, _) => 3,
+            (_, hv) if hv >= 2 => 2,
+            (_, 1) => 1,
+            _ => 0,
+        },
+        _ => match (h, v, d) {
+            (2, _, _) => 8,
+            (1, v, _) if v >= 1 => 7,
+            (1, _, d) if d >= 1 => 6,
+            (1, _, _) => 5,
+            (_, 2, _) => 4,
+            (_, 1, _) => 3,
+            (_, _, d) if d >= 2 => 2,
+            (_, _, 1) => 1,
+            _ => 0,
+        },
+    }
+}
+
+/*
+    Value of a magnitude known down to the given bit-plane, in the middle of its interval.
+*/
+fn reconstruction(magnitude: u32, plane: u32) -> f64 {
+    match plane {
+        0 => magnitude as f64,
+        _ => ((magnitude >> plane) << plane) as f64 + (1_u32 << (plane - 1)) as f64,
+    }
+}
+
+/*
+    State of a code-block during the coding: the magnitudes of its samples and their flags
+    (with a border of insignificant samples, so that every sample has 8 neighbours).
+*/
+struct BlockState {
+    width: usize,
+    height: usize,
+    orientation: Orientation,
+    vertically_causal: bool,
+    magnitudes: Vec<u32>,
+    flags: Vec<u8>,
+}
+
+impl BlockState {
+    fn new(width: usize, height: usize, orientation: Orientation, vertically_causal: bool) -> Self {
+        BlockState {
+            width,
+            height,
+            orientation,
+            vertically_causal,
+            magnitudes: vec![0; width * height],
+            flags: vec![0; (width + 2) * (height + 2)],
+        }
+    }
+
+    fn flag_index(&self, x: usize, y: usize) -> usize { (y + 1) * (self.width + 2) + x + 1 }
+
+    fn bit(&self, x: usize, y: usize, plane: u32) -> u8 {
+        ((self.magnitudes[y * self.width + x] >> plane) & 1) as u8
+    }
+
+    /*
+        Whether the neighbours below a sample are used (they are not with the vertically causal
+        mode when the sample is on the last line of a stripe).
+    */
+    fn uses_below(&self, y: usize) -> bool {
+        !(self.vertically_causal && y % 4 == 3)
+    }
+
+    /*
+        Get the number of significant horizontal, vertical and diagonal neighbours of a sample.
+    */
+    fn neighbours(&self, x: usize, y: usize) -> (u32, u32, u32) {
+        let i = self.flag_index(x, y);
+        let w = self.width + 2;
+        let significant = |j: usize| (self.flags[j] & SIGNIFICANT) as u32;
+
+        let h = significant(i - 1) + significant(i + 1);
+        let mut v = significant(i - w);
+        let mut d = significant(i - w - 1) + significant(i - w + 1);
+        if self.uses_below(y) {
+            v += significant(i + w);
+            d += significant(i + w - 1) + significant(i + w + 1);
+        }
+
+        (h, v, d)
+    }
+
+    /*
+        Get the context of the sign coding and the bit to xor the sign with (Table D.3 of the spec),
+        from the signs of the significant horizontal and vertical neighbours.
+    */
+    fn sign_context(&self, x: usize, y: usize) -> (usize, u8) {
+        let i = self.flag_index(x, y);
+        let w = self.width + 2;
+        let contribution = |j: usize| match self.flags[j] & (SIGNIFICANT | NEGATIVE) {
+            SIGNIFICANT => 1,
+            flags if flags & SIGNIFICANT != 0 => -1,
+            _ => 0,
+        };
+
+        let h = (contribution(i - 1) + contribution(i + 1)).clamp(-1, 1);
+        let below = if self.uses_below(y) { contribution(i + w) } else { 0 };
+        let v = (contribution(i - w) + below).clamp(-1, 1);
+
+        match (h, v) {
+            (1, 1) => (13, 0),
+            (1, 0) => (12, 0),
+            (1, _) => (11, 0),
+            (0, 1) => (10, 0),
+            (0, 0) => (9, 0),
+            (0, _) => (10, 1),
+            (_, 1) => (11, 1),
+            (_, 0) => (12, 1),
+            _ => (13, 1),
+        }
+    }
+
+    /*
+        Code the sign of a sample becoming significant in the given bit-plane, and get the decrease
+        of distortion.
+    */
+    fn code_significance<C: BitCoder>(&mut self, coder: &mut C, x: usize, y: usize, plane: u32) -> f64 {
+        let (context, xor) = self.sign_context(x, y);
+        let i = self.flag_index(x, y);
+        let negative = (self.flags[i] & NEGATIVE != 0) as u8;
+
+        let sign = coder.code(negative ^ xor, context) ^ xor;
+        self.flags[i] = (self.flags[i] & !NEGATIVE) | SIGNIFICANT | if sign == 1 { NEGATIVE } else { 0 };
+        self.magnitudes[y * self.width + x] |= 1 << plane;
+
+        let magnitude = self.magnitudes[y * self.width + x] as f64;
+        magnitude.powi(2) - (magnitude - reconstruction(magnitude as u32, plane)).powi(2)
+    }
+
+    /*
+        Code the significance of an insignificant sample in the given bit-plane (and its sign when it
+        becomes significant).
+    */
+    fn code_sample<C: BitCoder>(&mut self, coder: &mut C, x: usize, y: usize, plane: u32) -> f64 {
+        let (h, v, d) = self.neighbours(x, y);
+        let context = zero_coding_context(self.orientation, h, v, d);
+
+        match coder.code(self.bit(x, y, plane), context) {
+            1 => self.code_significance(coder, x, y, plane),
+            _ => 0.,
+        }
+    }
+
+    /*
+        Get the positions of the samples in the scan order: stripe by stripe, column by column.
+    */
+    fn scan(&self) -> Vec<(usize, usize)> {
+        let mut positions = Vec::with_capacity(self.width * self.height);
+        for stripe in (0..self.height).step_by(4) {
+            for x in 0..self.width {
+                for y in stripe..(stripe + 4).min(self.height) {
+                    positions.push((x, y));
+                }
+            }
+        }
+        positions
+    }
+
+    fn significance_propagation<C: BitCoder>(&mut self, coder: &mut C, plane: u32) -> f64 {
+        let mut distortion = 0.;
+
+        for (x, y) in self.scan() {
+            let i = self.flag_index(x, y);
+            let (h, v, d) = self.neighbours(x, y);
+            if self.flags[i] & SIGNIFICANT == 0 && h + v + d > 0 {
+                distortion += self.code_sample(coder, x, y, plane);
+                self.flags[i] |= VISITED;
+            }
+        }
+
+        distortion
+    }
+
+    fn magnitude_refinement<C: BitCoder>(&mut self, coder: &mut C, plane: u32) -> f64 {
+        let mut distortion = 0.;
+
+        for (x, y) in self.scan() {
+            let i = self.flag_index(x, y);
+            if self.flags[i] & (SIGNIFICANT | VISITED) != SIGNIFICANT {
+                continue;
+            }
+
+            let context = match self.neighbours(x, y) {
+                _ if self.flags[i] & REFINED != 0 => 16,
+                (0, 0, 0) => 14,
+                _ => 15,
+            };
+            let magnitude = self.magnitudes[y * self.width + x];
+            let before = (magnitude as f64 - reconstruction(magnitude, plane + 1)).powi(2);
+
+            if coder.code(self.bit(x, y, plane), context) == 1 {
+                self.magnitudes[y * self.width + x] |= 1 << plane;
+            }
+            self.flags[i] |= REFINED;
+
+            let magnitude = self.magnitudes[y * self.width + x];
+            distortion += before - (magnitude as f64 - reconstruction(magnitude, plane)).powi(2);
+        }
+
+        distortion
+    }
+
+    fn cleanup<C: BitCoder>(&mut self, coder: &mut C, plane: u32) -> f64 {
+        let mut distortion = 0.;
+
+        for stripe in (0..self.height).step_by(4) {
+            let end = (stripe + 4).min(self.height);
+            for x in 0..self.width {
+                let mut start = stripe;
+
+                // Run-length mode: a full column of insignificant samples without significant neighbours
+                let run = end - stripe == 4 && (stripe..end).all(|y| {
+                    self.flags[self.flag_index(x, y)] & (SIGNIFICANT | VISITED) == 0 && self.neighbours(x, y) == (0, 0, 0)
+                });
+                if run {
+                    let any = (stripe..end).any(|y| self.bit(x, y, plane) == 1) as u8;
+                    if coder.code(any, RUN_LENGTH_CONTEXT) == 0 {
+                        continue;
+                    }
+                    let first = (stripe..end).position(|y| self.bit(x, y, plane) == 1).unwrap_or(0) as u8;
+                    let high = coder.code(first >> 1, UNIFORM_CONTEXT);
+                    let low = coder.code(first & 1, UNIFORM_CONTEXT);
+                    let y = stripe + (2 * high + low) as usize;
+                    distortion += self.code_significance(coder, x, y, plane);
+                    start = y + 1;
+                }
+
+                for y in start..end {
+                    if self.flags[self.flag_index(x, y)] & (SIGNIFICANT | VISITED) == 0 {
+                        distortion += self.code_sample(coder, x, y, plane);
+                    }
+                }
+            }
+        }
+
+        for flags in self.flags.iter_mut() {
+            *flags &= !VISITED;
+        }
+
+        distortion
+    }
+
+    /*
+        Code one pass, and the segmentation symbols after a cleanup pass if needed.
+    */
+    fn code_pass<C: BitCoder>(&mut self, coder: &mut C, kind: PassKind, plane: u32, segmentation_symbols: bool) -> f64 {
+        match kind {
+            PassKind::SignificancePropagation => self.significance_propagation(coder, plane),
+            PassKind::MagnitudeRefinement => self.magnitude_refinement(coder, plane),
+            PassKind::Cleanup => {
+                let distortion = self.cleanup(coder, plane);
+                if segmentation_symbols {
+                    for bit in [1, 0, 1, 0] {
+                        coder.code(bit, UNIFORM_CONTEXT);
+                    }
+                }
+                distortion
+            },
+        }
+    }
+}
+
+/*
+    Tier-1 coder of the quantized subbands, splitting them in code-blocks of the given size
+    (64x64 by default, the size must be a power of 2 between 4 and 1024, with at most 4096 samples).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCoder {
+    block_width: u32,
+    block_height: u32,
+    style: CodeBlockStyle,
+}
+
+impl Default for BlockCoder {
+    fn default() -> Self { Self::new() }
+}
+
+impl BlockCoder {
+    pub fn new() -> Self {
+        BlockCoder {block_width: 64, block_height: 64, style: CodeBlockStyle::new()}
+    }
+
+    pub fn get_block_width(&self) -> u32 { self.block_width }
+    pub fn get_block_height(&self) -> u32 { self.block_height }
+    pub fn get_style(&self) -> CodeBlockStyle { self.style }
+
+    pub fn set_block_size(&mut self, width: u32, height: u32) {
+        let valid = |size: u32| size.is_power_of_two() && (4..=1024).contains(&size);
+        if !valid(width) || !valid(height) || width * height > 4096 {
+            panic!("Invalid code-block size {}x{}.", width, height);
+        }
+        self.block_width = width;
+        self.block_height = height;
+    }
+
+    pub fn set_style(&mut self, style: CodeBlockStyle) { self.style = style; }
+
+    /*
+        Get the coder with code-blocks no larger than the given size (powers of 2, which can be
+        smaller than 4, as the code-blocks must fit in the precincts).
+    */
+    pub fn limit_block_size(&self, width: u32, height: u32) -> BlockCoder {
+        BlockCoder {block_width: self.block_width.min(width), block_height: self.block_height.min(height), style: self.style}
+    }
+
+    /*
+        Code the code-block of a subband at the given position.
+    */
+    pub fn encode_block(&self, subband: &QuantizedSubband, x: u32, y: u32, width: u32, height: u32) -> CodeBlock {
+        let coefficients = subband.get_coefficients().slice((y as usize, x as usize), (height as usize, width as usize));
+        let mut state = BlockState::new(width as usize, height as usize, subband.get_orientation(), self.style.vertically_causal);
+
+        for row in 0..height as usize {
+            for col in 0..width as usize {
+                let q = coefficients[(row, col)];
+                state.magnitudes[row * width as usize + col] = q.unsigned_abs();
+                if q < 0 {
+                    let i = state.flag_index(col, row);
+                    state.flags[i] |= NEGATIVE;
+                }
+            }
+        }
+
+        let max = state.magnitudes.iter().copied().max().unwrap_or(0);
+        let plane_count = 32 - max.leading_zeros();
+        let zero_bit_planes = (subband.get_magnitude_bits() as u32).saturating_sub(plane_count) as u8;
+        if plane_count == 0 {
+            return CodeBlock::new(x, y, width, height, zero_bit_planes, Vec::new(), Vec::new());
+        }
+
+        let pass_count = 3 * plane_count as usize - 2;
+        let mut encoder = MqEncoder::new();
+        let mut passes = Vec::with_capacity(pass_count);
+
+        for index in 0..pass_count {
+            let (kind, offset) = PassKind::from_index(index);
+            let plane = plane_count - 1 - offset as u32;
+            let distortion = state.code_pass(&mut encoder, kind, plane, self.style.segmentation_symbols);
+
+            let terminated = self.style.terminate_all || index == pass_count - 1;
+            let length = if terminated {
+                if self.style.predictable_termination { encoder.flush_predictable(); } else { encoder.flush(); }
+                encoder.restart();
+                encoder.get_length()
+            } else {
+                // At most the byte being built and the two bytes of a flush are still to come
+                encoder.get_length() + 2
+            };
+            if self.style.reset {
+                encoder.reset_contexts();
+            }
+
+            passes.push(CodingPass::new(kind, length, distortion, terminated));
+        }
+
+        // A truncated codeword can not end with 0xFF (the decoder would see a marker)
+        let data = encoder.get_bytes().to_vec();
+        for pass in passes.iter_mut().filter(|pass| !pass.terminated) {
+            pass.length = pass.length.min(data.len());
+            if pass.length > 0 && data[pass.length - 1] == 0xFF {
+                pass.length -= 1;
+            }
+        }
+
+        CodeBlock::new(x, y, width, height, zero_bit_planes, passes, data)
+    }
+
+    /*
+        Get the quantization indices of a code-block back from its passes, with the number of their
+        lowest bit-planes which are not decoded (left to the dequantization).
+    */
+    pub fn decode_block(&self, block: &CodeBlock, orientation: Orientation, magnitude_bits: u8) -> (DMatrix<i32>, u8) {
+        let (width, height) = (block.width as usize, block.height as usize);
+        let passes = &block.passes;
+        if passes.is_empty() {
+            return (DMatrix::zeros(height, width), 0);
+        }
+
+        let plane_count = (magnitude_bits as u32).checked_sub(block.zero_bit_planes as u32)
+            .filter(|&count| count > 0)
+            .unwrap_or_else(|| panic!("The code-block has more zero bit-planes than magnitude bits."));
+        let mut state = BlockState::new(width, height, orientation, self.style.vertically_causal);
+        let data = &block.data;
+
+        // End of the codeword segment starting at the given pass
+        let segment_end = |first: usize| passes[first..].iter()
+            .find(|pass| pass.terminated)
+            .map_or(data.len(), |pass| pass.length.min(data.len()));
+        let mut decoder = MqDecoder::new(&data[..segment_end(0)]);
+        let mut last_plane = 0;
+
+        for index in 0..passes.len() {
+            if index > 0 && passes[index - 1].terminated {
+                let start = passes[index - 1].length.min(data.len());
+                decoder.restart(&data[start..segment_end(index).max(start)]);
+            }
+
+            let (kind, offset) = PassKind::from_index(index);
+            last_plane = (plane_count - 1).checked_sub(offset as u32)
+                .unwrap_or_else(|| panic!("The code-block has more passes than bit-planes."));
+            state.code_pass(&mut decoder, kind, last_plane, self.style.segmentation_symbols);
+
+            if self.style.reset {
+                decoder.reset_contexts();
+            }
+        }
+
+        let indices = DMatrix::from_fn(height, width, |row, col| {
+            let magnitude = state.magnitudes[row * width + col] as i32;
+            if state.flags[state.flag_index(col, row)] & NEGATIVE != 0 { -magnitude } else { magnitude }
+        });
+        (indices, last_plane as u8)
+    }
+
+    /*
+        Split a quantized subband in code-blocks and code them.
+    */
+    pub fn encode_subband(&self, subband: &QuantizedSubband) -> CodedSubband {
+        let (x0, y0) = subband.get_origin();
+        let columns = partition(x0, subband.get_width(), self.block_width);
+        let rows = partition(y0, subband.get_height(), self.block_height);
+
+        let blocks = rows.iter().flat_map(|&(y, height)| {
+            columns.iter().map(move |&(x, width)| self.encode_block(subband, x, y, width, height))
+        }).collect();
+
+        CodedSubband::new(
+            subband.get_orientation(),
+            subband.get_level(),
+            subband.get_origin(),
+            subband.get_width(),
+            subband.get_height(),
+            subband.get_step_size(),
+            subband.get_magnitude_bits(),
+            columns.len() as u32,
+            rows.len() as u32,
+            blocks,
+        )
+    }
+
+    /*
+        Get the code-blocks of a subband quantized with the given step size, without any pass (the
+        decoder adds the passes read from the packets).
+    */
+    pub fn empty_subband(&self, subband: &Subband, step_size: StepSize, magnitude_bits: u8) -> CodedSubband {
+        let (x0, y0) = subband.get_origin();
+        let columns = partition(x0, subband.get_width(), self.block_width);
+        let rows = partition(y0, subband.get_height(), self.block_height);
+
+        let blocks = rows.iter().flat_map(|&(y, height)| {
+            columns.iter().map(move |&(x, width)| CodeBlock::new(x, y, width, height, magnitude_bits, Vec::new(), Vec::new()))
+        }).collect();
+
+        CodedSubband::new(
+            subband.get_orientation(),
+            subband.get_level(),
+            subband.get_origin(),
+            subband.get_width(),
+            subband.get_height(),
+            step_size,
+            magnitude_bits,
+            columns.len() as u32,
+            rows.len() as u32,
+            blocks,
+        )
+    }
+
+    /*
+        Get the quantized subband back from its code-blocks.
+    */
+    pub fn decode_subband(&self, subband: &CodedSubband) -> QuantizedSubband {
+        let shape = (subband.height as usize, subband.width as usize);
+        let mut coefficients = DMatrix::zeros(shape.0, shape.1);
+        let mut missing_planes = DMatrix::zeros(shape.0, shape.1);
+
+        for block in &subband.blocks {
+            let (indices, planes) = self.decode_block(block, subband.orientation, subband.magnitude_bits);
+            let start = (block.y as usize, block.x as usize);
+            coefficients.slice_mut(start, indices.shape()).copy_from(&indices);
+            missing_planes.slice_mut(start, indices.shape()).fill(planes);
+        }
+
+        let mut quantized = QuantizedSubband::new(subband.orientation, subband.level, subband.origin, subband.step_size, subband.magnitude_bits, coefficients);
+        quantized.set_missing_planes(missing_planes);
+        quantized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Number of magnitude bits of the subbands coded
+    const MAGNITUDE_BITS: u8 = 12;
+
+    /*
+        Quantized subband with pseudo-random indices (from a linear congruential generator): mostly
+        small ones and zeros, with a few large ones, both positive and negative.
+    */
+    fn subband(width: usize, height: usize, orientation: Orientation, seed: u32) -> QuantizedSubband {
+        let mut state = seed;
+        let coefficients = DMatrix::from_fn(height, width, |_, _| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let magnitude = match (state >> 8) % 8 {
+                0..=2 => 0,
+                3..=6 => (state >> 12) % 16,
+                _ => (state >> 12) % (1 << MAGNITUDE_BITS),
+            } as i32;
+            if state & 0x10 != 0 { -magnitude } else { magnitude }
+        });
+        QuantizedSubband::new(orientation, 1, (0, 0), StepSize::new(10, 0), MAGNITUDE_BITS, coefficients)
+    }
+
+    /*
+        Code-block styles tested: the default one, and with the options of the COD marker segment.
+    */
+    fn styles() -> Vec<CodeBlockStyle> {
+        let mut terminate_all = CodeBlockStyle::new();
+        terminate_all.set_terminate_all(true);
+        let mut predictable = terminate_all;
+        predictable.set_predictable_termination(true);
+        let mut others = CodeBlockStyle::new();
+        others.set_reset(true);
+        others.set_vertically_causal(true);
+        others.set_segmentation_symbols(true);
+        let mut all = others;
+        all.set_terminate_all(true);
+        all.set_predictable_termination(true);
+
+        vec![CodeBlockStyle::new(), terminate_all, predictable, others, all]
+    }
+
+    fn block_coder(style: CodeBlockStyle) -> BlockCoder {
+        let mut block_coder = BlockCoder::new();
+        block_coder.set_style(style);
+        block_coder
+    }
+
+    /*
+        Get a code-block with only its first passes, as sent by the tier-2 coding.
+    */
+    fn truncate(block: &CodeBlock, pass_count: usize) -> CodeBlock {
+        let data = block.get_data()[..block.get_length(pass_count)].to_vec();
+        CodeBlock::new(block.get_x(), block.get_y(), block.width, block.height, block.get_zero_bit_planes(), block.passes[..pass_count].to_vec(), data)
+    }
+
+    /*
+        Keep the bits of the magnitude of an index from the given bit-plane.
+    */
+    fn truncated_index(q: i32, plane: u32) -> i32 {
+        let magnitude = (q.unsigned_abs() >> plane << plane) as i32;
+        if q < 0 { -magnitude } else { magnitude }
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let orientations = [Orientation::LL, Orientation::HL, Orientation::LH, Orientation::HH];
+        let sizes = [(1, 1), (1, 3), (2, 2), (3, 2), (4, 4), (5, 7), (16, 9), (64, 64)];
+
+        for style in styles() {
+            let block_coder = block_coder(style);
+            for (seed, &orientation) in orientations.iter().enumerate() {
+                for &(width, height) in &sizes {
+                    let subband = subband(width, height, orientation, seed as u32);
+                    let block = block_coder.encode_block(&subband, 0, 0, width as u32, height as u32);
+                    let (indices, missing_planes) = block_coder.decode_block(&block, orientation, MAGNITUDE_BITS);
+
+                    assert_eq!(&indices, subband.get_coefficients(), "{}x{} {:?} with {:?}", width, height, orientation, style);
+                    assert_eq!(missing_planes, 0);
+                    assert_eq!(block.get_passes().iter().all(|pass| pass.get_terminated()), style.get_terminate_all() || block.get_passes().len() <= 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn subband_round_trip() {
+        for (width, height) in [(1, 1), (3, 2), (13, 10), (70, 33)] {
+            let subband = subband(width, height, Orientation::HL, 7);
+            for block_size in [1, 2, 4, 64] {
+                let block_coder = BlockCoder::new().limit_block_size(block_size, block_size);
+                let decoded = block_coder.decode_subband(&block_coder.encode_subband(&subband));
+                assert_eq!(decoded.get_coefficients(), subband.get_coefficients(), "{}x{} in {}x{} blocks", width, height, block_size, block_size);
+                assert!(decoded.get_missing_planes().iter().all(|&planes| planes == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_passes() {
+        for style in styles() {
+            let block_coder = block_coder(style);
+            for (seed, (width, height)) in [(3, 3), (8, 5), (32, 32)].into_iter().enumerate() {
+                let subband = subband(width, height, Orientation::LH, seed as u32 + 10);
+                let coefficients = subband.get_coefficients();
+                let block = block_coder.encode_block(&subband, 0, 0, width as u32, height as u32);
+                let plane_count = (MAGNITUDE_BITS - block.get_zero_bit_planes()) as u32;
+
+                for pass_count in 1..=block.get_passes().len() {
+                    let (indices, missing_planes) = block_coder.decode_block(&truncate(&block, pass_count), Orientation::LH, MAGNITUDE_BITS);
+                    let (kind, offset) = PassKind::from_index(pass_count - 1);
+                    let plane = plane_count - 1 - offset as u32;
+                    assert_eq!(missing_planes as u32, plane);
+
+                    for (&decoded, &q) in indices.iter().zip(coefficients.iter()) {
+                        // A cleanup pass completes its bit-plane, the other passes only some of its samples
+                        match kind {
+                            PassKind::Cleanup => assert_eq!(decoded, truncated_index(q, plane), "{} passes with {:?}", pass_count, style),
+                            _ => assert!(
+                                decoded == truncated_index(q, plane) || decoded == truncated_index(q, plane + 1),
+                                "{} passes with {:?}: {} for {}", pass_count, style, decoded, q,
+                            ),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,698 @@
+use std::ops::Range;
+use crate::progression::{Position, Progression, packet_sequence};
+use crate::tag_tree::TagTree;
+use crate::tier1::{CodeBlockStyle, CodedSubband, CodingPass, PassKind};
+
+/*
+    Crate of the tier-2 coding: the packets gathering the coded code-blocks (Annex B of the spec).
+
+    A packet holds the contribution of the code-blocks of one precinct of one resolution level of one
+    component to one quality layer. Its header tells, for each code-block (subband by subband,
+    row by row):
+    - whether it is included in the layer (with a tag tree of the first layer of each code-block
+      the first time, with a single bit afterwards),
+    - its number of missing most significant bit-planes, the first time it is included (tag tree),
+    - the number of new coding passes,
+    - the length of the new data, on a number of bits which grows with the state Lblock of the code-block.
+    The body holds the new data of each included code-block, in the same order.
+
+    The precincts split each resolution level on a grid of the given size (powers of 2) anchored at 0,
+    and each subband of the resolution level on a grid twice as small (the same size for the LL
+    subband of the resolution 0). The code-blocks of a subband are no larger than its precincts, so
+    that each code-block belongs to a single precinct.
+*/
+
+/*
+    Writer of the bits of a packet header, from the most significant bit of each byte.
+    After a 0xFF byte, the most significant bit of the next byte is a stuffed 0, so that the header
+    never contains a marker.
+*/
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    free: u32,
+}
+
+impl Default for BitWriter {
+    fn default() -> Self { Self::new() }
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter {bytes: Vec::new(), buffer: 0, free: 8}
+    }
+
+    fn byte_out(&mut self) {
+        self.buffer = (self.buffer << 8) & 0xFFFF;
+        self.free = if self.buffer == 0xFF00 { 7 } else { 8 };
+        self.bytes.push((self.buffer >> 8) as u8);
+    }
+
+    pub fn write_bit(&mut self, bit: u8) {
+        if self.free == 0 {
+            self.byte_out();
+        }
+        self.free -= 1;
+        self.buffer |= (bit as u32) << self.free;
+    }
+
+    /*
+        Write the given number of bits of a value, the most significant first.
+    */
+    pub fn write_bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit(((value >> i) & 1) as u8);
+        }
+    }
+
+    /*
+        Get the bytes, the last one padded with 0 bits (and followed by a 0 byte if it is 0xFF).
+    */
+    pub fn finish(mut self) -> Vec<u8> {
+        self.byte_out();
+        if self.free == 7 {
+            self.byte_out();
+        }
+        self.bytes
+    }
+}
+
+/*
+    Reader of the bits of a packet header (0 bits are read past the end of the data).
+*/
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    available: u32,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader {data, position: 0, buffer: 0, available: 0}
+    }
+
+    fn byte_in(&mut self) {
+        self.buffer = (self.buffer << 8) & 0xFFFF;
+        self.available = if self.buffer == 0xFF00 { 7 } else { 8 };
+        if let Some(&byte) = self.data.get(self.position) {
+            self.buffer |= byte as u32;
+            self.position += 1;
+        }
+    }
+
+    pub fn read_bit(&mut self) -> u8 {
+        if self.available == 0 {
+            self.byte_in();
+        }
+        self.available -= 1;
+        ((self.buffer >> self.available) & 1) as u8
+    }
+
+    pub fn read_bits(&mut self, count: u32) -> u32 {
+        (0..count).fold(0, |value, _| (value << 1) | self.read_bit() as u32)
+    }
+
+    /*
+        Skip the padding bits of the last byte (and the 0 byte following a last 0xFF).
+    */
+    pub fn align(&mut self) {
+        if self.buffer & 0xFF == 0xFF {
+            self.byte_in();
+        }
+        self.available = 0;
+    }
+
+    /*
+        Get the number of bytes read.
+    */
+    pub fn get_position(&self) -> usize { self.position }
+}
+
+/*
+    Code the number of new coding passes of a code-block (Table B.4 of the spec).
+*/
+fn write_pass_count(writer: &mut BitWriter, count: usize) {
+    let count = count as u32;
+    match count {
+        1 => writer.write_bits(0, 1),
+        2 => writer.write_bits(0b10, 2),
+        3..=5 => writer.write_bits(0b1100 | (count - 3), 4),
+        6..=36 => writer.write_bits(0b1111 << 5 | (count - 6), 9),
+        37..=164 => writer.write_bits(0x1FF << 7 | (count - 37), 16),
+        _ => panic!("Too many coding passes in a packet: {}.", count),
+    }
+}
+
+fn read_pass_count(reader: &mut BitReader) -> usize {
+    if reader.read_bit() == 0 {
+        return 1;
+    }
+    if reader.read_bit() == 0 {
+        return 2;
+    }
+    match reader.read_bits(2) {
+        3 => match reader.read_bits(5) {
+            31 => 37 + reader.read_bits(7) as usize,
+            count => 6 + count as usize,
+        },
+        count => 3 + count as usize,
+    }
+}
+
+/*
+    State of a code-block of a precinct between the layers: whether it was already included,
+    its Lblock and the number of passes already sent.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockState {
+    included: bool,
+    lblock: u32,
+    pass_count: usize,
+}
+
+// Code-blocks of one subband in a precinct: their indices in the subband (row by row), and their number in a row
+type BandBlocks = (Vec<usize>, usize);
+
+/*
+    Code-blocks of one subband in a precinct, with their tag trees.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PrecinctBand {
+    width: usize,
+    height: usize,
+    blocks: Vec<usize>,
+    inclusion: TagTree,
+    zero_bit_planes: TagTree,
+    states: Vec<BlockState>,
+}
+
+impl PrecinctBand {
+    fn new((blocks, width): &BandBlocks) -> Self {
+        let height = if *width == 0 { 0 } else { blocks.len() / width };
+        PrecinctBand {
+            width: *width,
+            height,
+            blocks: blocks.clone(),
+            inclusion: TagTree::new(*width, height),
+            zero_bit_planes: TagTree::new(*width, height),
+            states: vec![BlockState {included: false, lblock: 3, pass_count: 0}; blocks.len()],
+        }
+    }
+}
+
+/*
+    Precinct of a resolution level of a component: the code-blocks of its subbands, whose
+    contributions to each layer are coded in one packet.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Precinct {
+    bands: Vec<PrecinctBand>,
+}
+
+impl Precinct {
+    /*
+        Create the precinct of the given code-blocks of each subband of a resolution level, for decoding.
+    */
+    fn empty(blocks: &[BandBlocks]) -> Self {
+        Precinct {bands: blocks.iter().map(PrecinctBand::new).collect()}
+    }
+
+    /*
+        Create the precinct of the given code-blocks of the subbands of a resolution level, for
+        encoding on the given number of layers: the tag trees hold the first layer and the zero
+        bit-planes of each code-block.
+    */
+    fn new(subbands: &[CodedSubband], blocks: &[BandBlocks], layer_count: usize) -> Self {
+        let mut precinct = Precinct::empty(blocks);
+
+        for (band, subband) in precinct.bands.iter_mut().zip(subbands) {
+            for (index, &block) in band.blocks.iter().enumerate() {
+                let block = &subband.get_blocks()[block];
+                let (x, y) = (index % band.width, index / band.width);
+                let first_layer = (0..layer_count).find(|&layer| block.get_layer_pass_count(layer) > 0);
+                band.inclusion.set_value(x, y, first_layer.map_or(u32::MAX, |layer| layer as u32));
+                band.zero_bit_planes.set_value(x, y, block.get_zero_bit_planes() as u32);
+            }
+        }
+
+        precinct
+    }
+
+    /*
+        Get the packet of the given layer: its header and its body.
+    */
+    fn encode_packet(&mut self, subbands: &[CodedSubband], layer: usize) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        let mut body = Vec::new();
+
+        let empty = self.bands.iter().zip(subbands).all(|(band, subband)| {
+            band.blocks.iter().zip(&band.states).all(|(&block, state)| subband.get_blocks()[block].get_layer_pass_count(layer) == state.pass_count)
+        });
+        writer.write_bit(!empty as u8);
+        if empty {
+            return writer.finish();
+        }
+
+        for (band, subband) in self.bands.iter_mut().zip(subbands) {
+            for (index, &block) in band.blocks.iter().enumerate() {
+                let block = &subband.get_blocks()[block];
+                let (x, y) = (index % band.width, index / band.width);
+                let state = &mut band.states[index];
+                let pass_count = block.get_layer_pass_count(layer);
+                let new_passes = pass_count - state.pass_count;
+
+                if state.included {
+                    writer.write_bit((new_passes > 0) as u8);
+                } else {
+                    band.inclusion.encode(&mut writer, x, y, layer as u32 + 1);
+                }
+                if new_passes == 0 {
+                    continue;
+                }
+                if !state.included {
+                    band.zero_bit_planes.encode(&mut writer, x, y, block.get_zero_bit_planes() as u32 + 1);
+                    state.included = true;
+                }
+                write_pass_count(&mut writer, new_passes);
+
+                // The new passes are split in codeword segments at the terminated passes
+                let mut segments = Vec::new();
+                let mut first = state.pass_count;
+                for (index, pass) in block.get_passes()[..pass_count].iter().enumerate().skip(first) {
+                    if pass.get_terminated() || index == pass_count - 1 {
+                        segments.push((index + 1 - first, block.get_length(index + 1) - block.get_length(first)));
+                        first = index + 1;
+                    }
+                }
+
+                // Lblock grows until every length fits on Lblock + log2(passes) bits
+                let increment = segments.iter().map(|&(passes, length)| {
+                    (usize::BITS - length.leading_zeros()).saturating_sub(state.lblock + passes.ilog2())
+                }).max().unwrap_or(0);
+                writer.write_bits(u32::MAX, increment);
+                writer.write_bit(0);
+                state.lblock += increment;
+                for (passes, length) in segments {
+                    writer.write_bits(length as u32, state.lblock + passes.ilog2());
+                }
+
+                body.extend_from_slice(&block.get_data()[block.get_length(state.pass_count)..block.get_length(pass_count)]);
+                state.pass_count = pass_count;
+            }
+        }
+
+        let mut packet = writer.finish();
+        packet.extend(body);
+        packet
+    }
+
+    /*
+        Read the packet of the given layer at the start of the data, add the new passes to the
+        code-blocks of the subbands (unless the data is skipped), and get the length of the packet.
+    */
+    fn decode_packet(&mut self, subbands: &mut [CodedSubband], layer: usize, data: &[u8], style: CodeBlockStyle, skip: bool) -> usize {
+        let mut reader = BitReader::new(data);
+        // Band, code-block, number of new passes and lengths of the codeword segments
+        let mut contributions: Vec<(usize, usize, usize, Vec<usize>)> = Vec::new();
+
+        if reader.read_bit() == 1 {
+            for (band_index, band) in self.bands.iter_mut().enumerate() {
+                for index in 0..band.states.len() {
+                    let (x, y) = (index % band.width, index / band.width);
+                    let state = &mut band.states[index];
+
+                    let included = if state.included {
+                        reader.read_bit() == 1
+                    } else {
+                        band.inclusion.decode(&mut reader, x, y, layer as u32 + 1)
+                    };
+                    if !included {
+                        continue;
+                    }
+                    if !state.included {
+                        let mut threshold = 1;
+                        while !band.zero_bit_planes.decode(&mut reader, x, y, threshold) {
+                            threshold += 1;
+                        }
+                        subbands[band_index].get_blocks_mut()[band.blocks[index]].set_zero_bit_planes((threshold - 1) as u8);
+                        state.included = true;
+                    }
+
+                    let new_passes = read_pass_count(&mut reader);
+                    while reader.read_bit() == 1 {
+                        state.lblock += 1;
+                    }
+                    let segments = match style.get_terminate_all() {
+                        true => vec![1; new_passes],
+                        false => vec![new_passes],
+                    };
+                    let lengths = segments.iter().map(|&passes| {
+                        reader.read_bits(state.lblock + (passes as u32).ilog2()) as usize
+                    }).collect();
+
+                    contributions.push((band_index, band.blocks[index], new_passes, lengths));
+                    state.pass_count += new_passes;
+                }
+            }
+        }
+
+        reader.align();
+        let mut position = reader.get_position();
+
+        for (band_index, index, new_passes, lengths) in contributions {
+            let total: usize = lengths.iter().sum();
+            if skip {
+                position += total;
+                continue;
+            }
+
+            let block = &mut subbands[band_index].get_blocks_mut()[index];
+            let start = block.get_passes().len();
+            let base = block.get_data().len();
+
+            let passes: Vec<CodingPass> = (0..new_passes).map(|pass| {
+                let kind = PassKind::from_index(start + pass).0;
+                match style.get_terminate_all() {
+                    true => CodingPass::new(kind, base + lengths[..=pass].iter().sum::<usize>(), 0., true),
+                    false => CodingPass::new(kind, base + total, 0., false),
+                }
+            }).collect();
+
+            // A truncated codestream only gives part of the data
+            let end = (position + total).min(data.len());
+            block.add_passes(&passes, &data[position.min(end)..end]);
+            position += total;
+        }
+
+        position
+    }
+}
+
+/*
+    Get the ranges of the subbands of each resolution level in a list of subbands in resolution order
+    (LL, then HL, LH and HH of each level from the coarsest to the finest).
+*/
+pub fn resolution_ranges(subband_count: usize) -> Vec<Range<usize>> {
+    let resolutions = (1..subband_count).step_by(3).map(|start| start..start + 3);
+    std::iter::once(0..subband_count.min(1)).chain(resolutions).collect()
+}
+
+/*
+    Size of the precincts of a resolution level: the exponents (PPx, PPy) of their width and height.
+*/
+pub type PrecinctSize = (u8, u8);
+
+// Size of the default precincts, as large as the codestream allows
+pub const DEFAULT_PRECINCT_SIZE: PrecinctSize = (15, 15);
+
+/*
+    Get the size of the precincts of a resolution level in its subbands, which is also the
+    largest size of their code-blocks.
+*/
+pub fn precinct_block_size(resolution: usize, (width, height): PrecinctSize) -> (u32, u32) {
+    let shift = (resolution > 0) as u8;
+    (1 << (width - shift), 1 << (height - shift))
+}
+
+/*
+    Get the bounds of a resolution level from its subbands (in resolution order): the ones of the LL
+    subband for the resolution 0, otherwise the sums of the bounds of the lowpass and highpass
+    subbands in each direction.
+*/
+fn resolution_bounds(subbands: &[CodedSubband]) -> ((u32, u32), (u32, u32)) {
+    let end = |subband: &CodedSubband| (subband.get_origin().0 + subband.get_width(), subband.get_origin().1 + subband.get_height());
+    match subbands {
+        [ll] => (ll.get_origin(), end(ll)),
+        [hl, lh, _] => (
+            (lh.get_origin().0 + hl.get_origin().0, hl.get_origin().1 + lh.get_origin().1),
+            (end(lh).0 + end(hl).0, end(hl).1 + end(lh).1),
+        ),
+        _ => panic!("A resolution level has 1 or 3 subbands, not {}.", subbands.len()),
+    }
+}
+
+/*
+    Get the indices of the precincts (with the given exponent of their size) covering the interval
+    [start, end) of a resolution level.
+*/
+fn precinct_range(start: u32, end: u32, exponent: u8) -> Range<u32> {
+    match start < end {
+        true => start >> exponent..((end - 1) >> exponent) + 1,
+        false => 0..0,
+    }
+}
+
+/*
+    Split the code-blocks of the subbands of a resolution level between its precincts (in raster order).
+*/
+fn precinct_blocks(subbands: &[CodedSubband], resolution: usize, size: PrecinctSize) -> Vec<Vec<BandBlocks>> {
+    let ((x0, y0), (x1, y1)) = resolution_bounds(subbands);
+    let (columns, rows) = (precinct_range(x0, x1, size.0), precinct_range(y0, y1, size.1));
+    let (width, height) = precinct_block_size(resolution, size);
+    let mut precincts = vec![vec![(Vec::new(), 0); subbands.len()]; columns.len() * rows.len()];
+
+    for (band, subband) in subbands.iter().enumerate() {
+        let (origin_x, origin_y) = subband.get_origin();
+        for (index, block) in subband.get_blocks().iter().enumerate() {
+            let (column, row) = ((origin_x + block.get_x()) / width, (origin_y + block.get_y()) / height);
+            let precinct = (row - rows.start) as usize * columns.len() + (column - columns.start) as usize;
+            let (blocks, wide): &mut BandBlocks = &mut precincts[precinct][band];
+            if blocks.first().is_none_or(|&first| subband.get_blocks()[first].get_y() == block.get_y()) {
+                *wide += 1;
+            }
+            blocks.push(index);
+        }
+    }
+
+    precincts
+}
+
+/*
+    Layout of the packets of a tile: the progressions, the size of the precincts of each resolution
+    level (the default size beyond the given ones), and the origin of the tile on the reference grid
+    with the subsampling factors of each component, which place the precincts on the reference grid
+    for the progressions.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketLayout {
+    progressions: Vec<Progression>,
+    precinct_sizes: Vec<PrecinctSize>,
+    tile_origin: (u32, u32),
+    factors: Vec<(u32, u32)>,
+}
+
+impl PacketLayout {
+    pub fn new(progressions: Vec<Progression>, precinct_sizes: Vec<PrecinctSize>, tile_origin: (u32, u32), factors: Vec<(u32, u32)>) -> Self {
+        PacketLayout {progressions, precinct_sizes, tile_origin, factors}
+    }
+
+    pub fn get_precinct_size(&self, resolution: usize) -> PrecinctSize {
+        self.precinct_sizes.get(resolution).copied().unwrap_or(DEFAULT_PRECINCT_SIZE)
+    }
+
+    /*
+        Get the precincts of each resolution of each component, from the coded subbands (in
+        resolution order) of the components, for encoding on the given number of layers or for decoding.
+    */
+    fn get_precincts(&self, components: &[Vec<CodedSubband>], layer_count: Option<usize>) -> Vec<Vec<Vec<Precinct>>> {
+        components.iter().map(|subbands| {
+            resolution_ranges(subbands.len()).into_iter().enumerate().map(|(resolution, range)| {
+                let subbands = &subbands[range];
+                precinct_blocks(subbands, resolution, self.get_precinct_size(resolution)).iter().map(|blocks| match layer_count {
+                    Some(layer_count) => Precinct::new(subbands, blocks, layer_count),
+                    None => Precinct::empty(blocks),
+                }).collect()
+            }).collect()
+        }).collect()
+    }
+
+    /*
+        Get the positions of the precincts of each resolution of each component on the reference
+        grid: the progressions by position meet a precinct at its top left corner, or at the corner
+        of the tile for the first precincts when they start before the tile.
+    */
+    fn get_positions(&self, components: &[Vec<CodedSubband>]) -> Vec<Vec<Vec<Position>>> {
+        components.iter().zip(&self.factors).map(|(subbands, &(dx, dy))| {
+            let ranges = resolution_ranges(subbands.len());
+            let level_count = ranges.len() - 1;
+
+            ranges.into_iter().enumerate().map(|(resolution, range)| {
+                let ((x0, y0), (x1, y1)) = resolution_bounds(&subbands[range]);
+                let (width, height) = self.get_precinct_size(resolution);
+                // Position along one direction of the precinct k
+                let position = |k: u32, start: u32, exponent: u8, factor: u32, origin: u32| -> u64 {
+                    match k == start >> exponent && !start.is_multiple_of(1 << exponent) {
+                        true => origin as u64,
+                        false => ((k as u64) << (exponent as usize + level_count - resolution)) * factor as u64,
+                    }
+                };
+
+                let (columns, rows) = (precinct_range(x0, x1, width), precinct_range(y0, y1, height));
+                rows.flat_map(|row| columns.clone().map(move |column| (row, column))).map(|(row, column)| (
+                    position(row, y0, height, dy, self.tile_origin.1),
+                    position(column, x0, width, dx, self.tile_origin.0),
+                )).collect()
+            }).collect()
+        }).collect()
+    }
+}
+
+/*
+    Get the packets of a tile from the coded subbands of its components (in resolution order),
+    in the order of the progressions.
+*/
+pub fn encode_packets(components: &[Vec<CodedSubband>], layer_count: usize, layout: &PacketLayout) -> Vec<u8> {
+    let mut precincts = layout.get_precincts(components, Some(layer_count));
+    let mut data = Vec::new();
+
+    for [layer, resolution, component, precinct] in packet_sequence(&layout.progressions, layer_count, &layout.get_positions(components)) {
+        let range = resolution_ranges(components[component].len())[resolution].clone();
+        data.extend(precincts[component][resolution][precinct].encode_packet(&components[component][range], layer));
+    }
+
+    data
+}
+
+/*
+    Read the packets of a tile, in the order of the progressions, into the code-blocks of the subbands
+    of its components, and get the number of bytes read. Only the data of the first decoded layers is
+    kept. A truncated tile is read as far as possible.
+*/
+pub fn decode_packets(data: &[u8], components: &mut [Vec<CodedSubband>], layer_count: usize, decoded_layers: usize, layout: &PacketLayout, style: CodeBlockStyle) -> usize {
+    let mut precincts = layout.get_precincts(components, None);
+    let mut position = 0;
+
+    for [layer, resolution, component, precinct] in packet_sequence(&layout.progressions, layer_count, &layout.get_positions(components)) {
+        if position >= data.len() {
+            return data.len();
+        }
+        let range = resolution_ranges(components[component].len())[resolution].clone();
+        let skip = layer >= decoded_layers;
+        position += precincts[component][resolution][precinct].decode_packet(&mut components[component][range], layer, &data[position..], style, skip);
+    }
+
+    position.min(data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::DMatrix;
+    use super::*;
+    use crate::quantization::{QuantizedSubband, StepSize};
+    use crate::subband::Orientation;
+    use crate::tier1::{BlockCoder, CodeBlock};
+
+    const LAYER_COUNT: usize = 3;
+
+    #[test]
+    fn bit_stuffing() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0xFF, 8);
+        writer.write_bits(0x7F, 7);
+        writer.write_bits(0x1FF, 9);
+        writer.write_bits(0b101, 3);
+        let bytes = writer.finish();
+
+        // Each 0xFF byte is followed by a stuffed 0 bit
+        assert_eq!(bytes.iter().filter(|&&byte| byte == 0xFF).count(), 2);
+        assert!(bytes.windows(2).filter(|pair| pair[0] == 0xFF).all(|pair| pair[1] < 0x80));
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!([reader.read_bits(8), reader.read_bits(7), reader.read_bits(9), reader.read_bits(3)], [0xFF, 0x7F, 0x1FF, 0b101]);
+        reader.align();
+        assert_eq!(reader.get_position(), bytes.len());
+    }
+
+    #[test]
+    fn pass_count_codewords() {
+        let mut writer = BitWriter::new();
+        for count in 1..=164 {
+            write_pass_count(&mut writer, count);
+        }
+        let bytes = writer.finish();
+
+        let mut reader = BitReader::new(&bytes);
+        for count in 1..=164 {
+            assert_eq!(read_pass_count(&mut reader), count);
+        }
+    }
+
+    /*
+        Two coded subbands of pseudo-random indices (from a linear congruential generator), in
+        3x2 code-blocks of 16x16 samples, split in layers: nothing in the first one, then half of
+        the passes of some code-blocks, then all the passes.
+    */
+    fn subbands(style: CodeBlockStyle) -> Vec<CodedSubband> {
+        let mut block_coder = BlockCoder::new();
+        block_coder.set_block_size(16, 16);
+        block_coder.set_style(style);
+        let mut state = 3_u32;
+
+        [Orientation::HL, Orientation::LH].iter().map(|&orientation| {
+            let coefficients = DMatrix::from_fn(24, 40, |_, _| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let magnitude = ((state >> 8) % 1024) >> ((state >> 20) % 8);
+                if state & 0x10 != 0 { -(magnitude as i32) } else { magnitude as i32 }
+            });
+            let mut subband = block_coder.encode_subband(&QuantizedSubband::new(orientation, 1, (0, 0), StepSize::new(10, 0), 10, coefficients));
+            for (index, block) in subband.get_blocks_mut().iter_mut().enumerate() {
+                let count = block.get_passes().len();
+                let half = if index % 3 == 0 { 0 } else { count / 2 };
+                block.set_layers(vec![0, half, count]);
+            }
+            subband
+        }).collect()
+    }
+
+    /*
+        Get the subbands with the same code-blocks, without any pass.
+    */
+    fn empty(subbands: &[CodedSubband]) -> Vec<CodedSubband> {
+        subbands.iter().map(|subband| {
+            let blocks = subband.get_blocks().iter().map(|block| {
+                CodeBlock::new(block.get_x(), block.get_y(), block.get_width(), block.get_height(), 0, Vec::new(), Vec::new())
+            }).collect();
+            CodedSubband::new(
+                subband.get_orientation(), subband.get_level(), subband.get_origin(), subband.get_width(), subband.get_height(),
+                subband.get_step_size(), subband.get_magnitude_bits(), subband.get_blocks_wide(), subband.get_blocks_high(), blocks,
+            )
+        }).collect()
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let mut terminate_all = CodeBlockStyle::new();
+        terminate_all.set_terminate_all(true);
+
+        for style in [CodeBlockStyle::new(), terminate_all] {
+            let subbands = subbands(style);
+            let blocks: Vec<BandBlocks> = subbands.iter().map(|subband| {
+                ((0..subband.get_blocks().len()).collect(), subband.get_blocks_wide() as usize)
+            }).collect();
+
+            let mut precinct = Precinct::new(&subbands, &blocks, LAYER_COUNT);
+            let packets: Vec<Vec<u8>> = (0..LAYER_COUNT).map(|layer| precinct.encode_packet(&subbands, layer)).collect();
+            // An empty packet is a single 0 bit, and some lengths need more bits than the initial Lblock
+            assert_eq!(packets[0], [0]);
+            assert!(precinct.bands.iter().flat_map(|band| &band.states).any(|state| state.lblock > 3));
+
+            let mut decoded = empty(&subbands);
+            let mut decoder = Precinct::empty(&blocks);
+            for (layer, packet) in packets.iter().enumerate() {
+                assert_eq!(decoder.decode_packet(&mut decoded, layer, packet, style, false), packet.len());
+
+                for (subband, decoded) in subbands.iter().zip(&decoded) {
+                    for (block, decoded) in subband.get_blocks().iter().zip(decoded.get_blocks()) {
+                        let pass_count = block.get_layer_pass_count(layer);
+                        assert_eq!(decoded.get_passes().len(), pass_count);
+                        assert_eq!(decoded.get_data()[..], block.get_data()[..block.get_length(pass_count)]);
+                        if pass_count > 0 {
+                            assert_eq!(decoded.get_zero_bit_planes(), block.get_zero_bit_planes());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}